@@ -0,0 +1,9 @@
+fn main() {
+    // The embedded assets are read by a macro, so changes to them are unknown
+    // to cargo otherwise.
+    if std::env::var_os("CARGO_FEATURE_EMBED").is_some() {
+        println!("cargo:rerun-if-changed=../static");
+    } else {
+        println!("cargo:rerun-if-changed=build.rs");
+    }
+}
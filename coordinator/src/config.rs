@@ -0,0 +1,953 @@
+//! Configuration of the coordinator.
+//!
+//! Every setting has a default, which may be overridden in turn by a TOML
+//! file, by environment variables, or by Shuttle secrets, and by command line
+//! flags.
+
+#[cfg(feature = "turn-server")]
+use std::net::IpAddr;
+#[cfg(feature = "std")]
+use std::net::SocketAddr;
+use std::{
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use protocol::IceServer;
+use serde::Deserialize;
+use sha1::Sha1;
+use uuid::Uuid;
+
+/// Environment variable holding the path of the configuration file.
+#[cfg(feature = "std")]
+const CONFIG_VAR: &str = "COORDINATOR_CONFIG";
+
+/// STUN server handed to peers when no ICE server is configured.
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// Log filter when none is configured.
+const DEFAULT_LOG: &str = "coordinator=trace";
+
+/// How long ephemeral TURN credentials are valid.
+const TURN_CREDENTIAL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Configuration of the coordinator.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the signaling server listens on.
+    #[cfg(feature = "std")]
+    pub bind_addr: SocketAddr,
+    /// Path every route is served under, starting with a slash.
+    pub base_path: String,
+    /// Directory of the peer web page, `None` to serve the one embedded at
+    /// build time.
+    pub static_dir: Option<PathBuf>,
+    /// Log filter, in the syntax of `RUST_LOG`.
+    pub log: String,
+    pub ice: IceConfig,
+    /// Maximum number of participants in a single meeting.
+    pub max_participants: u32,
+    /// How long the seat of a participant is kept after its connection drops,
+    /// waiting for the session to be resumed.
+    pub resume_grace_period: Duration,
+    /// How long a participant alone in a meeting waits for others, before its
+    /// session ends.
+    pub wait_timeout: Duration,
+    #[cfg(feature = "turn-server")]
+    pub turn_server: TurnServerConfig,
+    /// Certificate to serve HTTPS with, instead of HTTP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// URL of the Redis server to store the meetings in, shared with other
+    /// coordinators, instead of the memory of this process.
+    #[cfg(feature = "redis")]
+    pub redis_url: Option<String>,
+    /// URL of the NATS server to store the meetings in, shared with other
+    /// coordinators, instead of the memory of this process.
+    #[cfg(feature = "nats")]
+    pub nats_url: Option<String>,
+}
+
+/// Settings of a single configuration source, missing ones being left to the
+/// sources of lower precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    #[cfg(feature = "std")]
+    bind_addr: Option<SocketAddr>,
+    base_path: Option<String>,
+    static_dir: Option<PathBuf>,
+    log: Option<String>,
+    ice_servers: Option<Vec<IceServer>>,
+    turn_secret: Option<String>,
+    max_participants: Option<u32>,
+    /// In seconds.
+    resume_grace_period: Option<u64>,
+    /// In seconds.
+    wait_timeout: Option<u64>,
+    #[cfg(feature = "turn-server")]
+    turn_listen_addr: Option<SocketAddr>,
+    #[cfg(feature = "turn-server")]
+    turn_public_ip: Option<IpAddr>,
+    #[cfg(feature = "tls")]
+    tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    tls_key: Option<PathBuf>,
+    #[cfg(feature = "redis")]
+    redis_url: Option<String>,
+    #[cfg(feature = "nats")]
+    nats_url: Option<String>,
+}
+
+/// Command line flags of the coordinator.
+#[cfg(feature = "std")]
+#[derive(Debug, clap::Parser)]
+#[command(version, about = "Coordinator of Meeting.rs")]
+struct Cli {
+    /// TOML configuration file.
+    #[arg(short, long, env = CONFIG_VAR)]
+    config: Option<PathBuf>,
+    /// Address the signaling server listens on [default: 0.0.0.0:3000].
+    #[arg(long)]
+    bind_addr: Option<SocketAddr>,
+    /// Path every route is served under, such as /meet/ [default: /].
+    #[arg(long)]
+    base_path: Option<String>,
+    /// Directory of the peer web page [default: static, or the embedded one].
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+    /// Log filter, in the syntax of `RUST_LOG` [default: coordinator=trace].
+    #[arg(long)]
+    log: Option<String>,
+    /// JSON array of the ICE servers handed to peers [default: Google STUN server].
+    #[arg(long)]
+    ice_servers: Option<String>,
+    /// Secret to mint TURN credentials with. Prefer `COORDINATOR_TURN_SECRET`, as flags
+    /// are visible to other users of the host.
+    #[arg(long)]
+    turn_secret: Option<String>,
+    /// Maximum number of participants in a single meeting [default: 6].
+    #[arg(long)]
+    max_participants: Option<u32>,
+    /// Seconds to keep the seat of a disconnected participant [default: 30].
+    #[arg(long)]
+    resume_grace_period: Option<u64>,
+    /// Seconds a participant alone in a meeting waits for others [default: 300].
+    #[arg(long)]
+    wait_timeout: Option<u64>,
+    /// Address the embedded STUN and TURN server listens on [default: 0.0.0.0:3478].
+    #[cfg(feature = "turn-server")]
+    #[arg(long)]
+    turn_listen_addr: Option<SocketAddr>,
+    /// Public IP address of the embedded TURN server, which peers reach.
+    #[cfg(feature = "turn-server")]
+    #[arg(long)]
+    turn_public_ip: Option<IpAddr>,
+    /// PEM certificate chain to serve HTTPS with, reloaded when it changes.
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate, reloaded when it changes.
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// Redis server to store the meetings in, such as redis://127.0.0.1/.
+    #[cfg(feature = "redis")]
+    #[arg(long)]
+    redis_url: Option<String>,
+    /// NATS server with JetStream to store the meetings in, such as nats://127.0.0.1:4222.
+    #[cfg(feature = "nats")]
+    #[arg(long)]
+    nats_url: Option<String>,
+}
+
+/// Invalid configuration, reported at startup.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    #[cfg(feature = "std")]
+    Read(PathBuf, std::io::Error),
+    /// The configuration file is not valid TOML for the configuration.
+    #[cfg(feature = "std")]
+    Parse(PathBuf, toml::de::Error),
+    /// A setting has an invalid value.
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            ConfigError::Read(path, error) => {
+                write!(f, "cannot read {}: {error}", path.display())
+            }
+            #[cfg(feature = "std")]
+            ConfigError::Parse(path, error) => {
+                write!(f, "invalid configuration in {}: {error}", path.display())
+            }
+            ConfigError::Invalid(setting, reason) => write!(f, "invalid `{setting}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration from the file, the environment variables and
+    /// the flags of the command line.
+    #[cfg(feature = "std")]
+    pub fn load() -> Result<Config, ConfigError> {
+        let cli = <Cli as clap::Parser>::parse();
+        let file = match &cli.config {
+            Some(path) => Layer::from_file(path)?,
+            None => Layer::default(),
+        };
+        let env = Layer::from_vars(|name| std::env::var(name).ok())?;
+        let cli = Layer {
+            bind_addr: cli.bind_addr,
+            base_path: cli.base_path,
+            static_dir: cli.static_dir,
+            log: cli.log,
+            ice_servers: cli
+                .ice_servers
+                .map(|servers| parse_ice_servers("ice_servers", &servers))
+                .transpose()?,
+            turn_secret: cli.turn_secret,
+            max_participants: cli.max_participants,
+            resume_grace_period: cli.resume_grace_period,
+            wait_timeout: cli.wait_timeout,
+            #[cfg(feature = "turn-server")]
+            turn_listen_addr: cli.turn_listen_addr,
+            #[cfg(feature = "turn-server")]
+            turn_public_ip: cli.turn_public_ip,
+            #[cfg(feature = "tls")]
+            tls_cert: cli.tls_cert,
+            #[cfg(feature = "tls")]
+            tls_key: cli.tls_key,
+            #[cfg(feature = "redis")]
+            redis_url: cli.redis_url,
+            #[cfg(feature = "nats")]
+            nats_url: cli.nats_url,
+        };
+        Config::from_layer(file.merge(env).merge(cli))
+    }
+
+    /// Loads the configuration from Shuttle secrets, named as the environment
+    /// variables.
+    #[cfg(feature = "shuttle")]
+    pub fn from_secrets(secrets: &shuttle_runtime::SecretStore) -> Result<Config, ConfigError> {
+        Config::from_layer(Layer::from_vars(|name| secrets.get(name))?)
+    }
+
+    /// Applies the defaults to the missing settings, and validates the others.
+    fn from_layer(layer: Layer) -> Result<Config, ConfigError> {
+        let default = Config::default();
+
+        #[cfg(feature = "tls")]
+        let tls = match (layer.tls_cert, layer.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            (Some(_), None) => {
+                return Err(ConfigError::Invalid(
+                    "tls_key",
+                    "should be set along with `tls_cert`".into(),
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "tls_cert",
+                    "should be set along with `tls_key`".into(),
+                ))
+            }
+        };
+
+        let config = Config {
+            #[cfg(feature = "std")]
+            bind_addr: layer.bind_addr.unwrap_or(default.bind_addr),
+            base_path: layer.base_path.unwrap_or(default.base_path),
+            static_dir: layer.static_dir.or(default.static_dir),
+            log: layer.log.unwrap_or(default.log),
+            ice: IceConfig {
+                servers: layer.ice_servers.unwrap_or(default.ice.servers),
+                turn_secret: layer.turn_secret,
+            },
+            max_participants: layer.max_participants.unwrap_or(default.max_participants),
+            resume_grace_period: layer
+                .resume_grace_period
+                .map(Duration::from_secs)
+                .unwrap_or(default.resume_grace_period),
+            wait_timeout: layer
+                .wait_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.wait_timeout),
+            #[cfg(feature = "turn-server")]
+            turn_server: TurnServerConfig {
+                listen_addr: layer
+                    .turn_listen_addr
+                    .unwrap_or(default.turn_server.listen_addr),
+                public_ip: layer.turn_public_ip,
+            },
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "redis")]
+            redis_url: layer.redis_url,
+            #[cfg(feature = "nats")]
+            nats_url: layer.nats_url,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the settings are usable.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.base_path.starts_with('/') {
+            return Err(ConfigError::Invalid(
+                "base_path",
+                format!("{} should start with a slash", self.base_path),
+            ));
+        }
+        if let Some(c) = self
+            .base_path
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || "/-._~".contains(*c)))
+        {
+            return Err(ConfigError::Invalid(
+                "base_path",
+                format!("{} should not contain {c:?}", self.base_path),
+            ));
+        }
+
+        if let Some(static_dir) = self.static_dir.as_ref().filter(|dir| !dir.is_dir()) {
+            return Err(ConfigError::Invalid(
+                "static_dir",
+                format!("{} is not a directory", static_dir.display()),
+            ));
+        }
+
+        if let Err(error) = tracing_subscriber::EnvFilter::try_new(&self.log) {
+            return Err(ConfigError::Invalid("log", error.to_string()));
+        }
+
+        for server in &self.ice.servers {
+            if server.urls.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "ice_servers",
+                    "every server should have URLs".into(),
+                ));
+            }
+            if let Some(url) = server.urls.iter().find(|url| {
+                !["stun:", "stuns:", "turn:", "turns:"]
+                    .iter()
+                    .any(|scheme| url.starts_with(scheme))
+            }) {
+                return Err(ConfigError::Invalid(
+                    "ice_servers",
+                    format!("{url} is not a STUN or TURN URL"),
+                ));
+            }
+        }
+        if self.ice.turn_secret.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(
+                "turn_secret",
+                "should not be empty".into(),
+            ));
+        }
+
+        if self.max_participants < 2 {
+            return Err(ConfigError::Invalid(
+                "max_participants",
+                "a meeting needs at least 2 participants".into(),
+            ));
+        }
+        if self.wait_timeout.is_zero() {
+            return Err(ConfigError::Invalid(
+                "wait_timeout",
+                "should be at least a second".into(),
+            ));
+        }
+
+        #[cfg(feature = "turn-server")]
+        if self.turn_server.public_ip.is_none() {
+            return Err(ConfigError::Invalid(
+                "turn_public_ip",
+                "should be set to the public IP address of the TURN server".into(),
+            ));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            for (setting, path) in [("tls_cert", &tls.cert), ("tls_key", &tls.key)] {
+                if !path.is_file() {
+                    return Err(ConfigError::Invalid(
+                        setting,
+                        format!("{} is not a file", path.display()),
+                    ));
+                }
+            }
+        }
+
+        #[cfg(feature = "redis")]
+        if let Some(url) = &self.redis_url {
+            if let Err(error) = redis::Client::open(url.as_str()) {
+                return Err(ConfigError::Invalid("redis_url", error.to_string()));
+            }
+        }
+
+        #[cfg(feature = "nats")]
+        if let Some(url) = &self.nats_url {
+            if let Err(error) = url.parse::<async_nats::ServerAddr>() {
+                return Err(ConfigError::Invalid("nats_url", error.to_string()));
+            }
+            #[cfg(feature = "redis")]
+            if self.redis_url.is_some() {
+                return Err(ConfigError::Invalid(
+                    "nats_url",
+                    "should not be set along with `redis_url`".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            #[cfg(feature = "std")]
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            base_path: "/".into(),
+            static_dir: (!cfg!(feature = "embed")).then(|| "static".into()),
+            log: DEFAULT_LOG.into(),
+            ice: IceConfig::new(
+                vec![IceServer {
+                    urls: vec![DEFAULT_STUN_SERVER.into()],
+                    username: None,
+                    credential: None,
+                }],
+                None,
+            ),
+            max_participants: 6,
+            resume_grace_period: Duration::from_secs(30),
+            wait_timeout: Duration::from_secs(5 * 60),
+            #[cfg(feature = "turn-server")]
+            turn_server: TurnServerConfig {
+                listen_addr: SocketAddr::from(([0, 0, 0, 0], 3478)),
+                public_ip: None,
+            },
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "redis")]
+            redis_url: None,
+            #[cfg(feature = "nats")]
+            nats_url: None,
+        }
+    }
+}
+
+impl Layer {
+    #[cfg(feature = "std")]
+    fn from_file(path: &std::path::Path) -> Result<Layer, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| ConfigError::Read(path.to_owned(), error))?;
+        toml::from_str(&content).map_err(|error| ConfigError::Parse(path.to_owned(), error))
+    }
+
+    /// Reads the settings from variables, such as environment variables.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Layer, ConfigError> {
+        Ok(Layer {
+            #[cfg(feature = "std")]
+            bind_addr: parse_var(&var, "COORDINATOR_BIND_ADDR")?,
+            base_path: var("COORDINATOR_BASE_PATH"),
+            static_dir: var("COORDINATOR_STATIC_DIR").map(PathBuf::from),
+            log: var("RUST_LOG"),
+            ice_servers: var("COORDINATOR_ICE_SERVERS")
+                .map(|servers| parse_ice_servers("COORDINATOR_ICE_SERVERS", &servers))
+                .transpose()?,
+            turn_secret: var("COORDINATOR_TURN_SECRET"),
+            max_participants: parse_var(&var, "COORDINATOR_MAX_PARTICIPANTS")?,
+            resume_grace_period: parse_var(&var, "COORDINATOR_RESUME_GRACE_PERIOD")?,
+            wait_timeout: parse_var(&var, "COORDINATOR_WAIT_TIMEOUT")?,
+            #[cfg(feature = "turn-server")]
+            turn_listen_addr: parse_var(&var, "COORDINATOR_TURN_LISTEN_ADDR")?,
+            #[cfg(feature = "turn-server")]
+            turn_public_ip: parse_var(&var, "COORDINATOR_TURN_PUBLIC_IP")?,
+            #[cfg(feature = "tls")]
+            tls_cert: var("COORDINATOR_TLS_CERT").map(PathBuf::from),
+            #[cfg(feature = "tls")]
+            tls_key: var("COORDINATOR_TLS_KEY").map(PathBuf::from),
+            #[cfg(feature = "redis")]
+            redis_url: var("COORDINATOR_REDIS_URL"),
+            #[cfg(feature = "nats")]
+            nats_url: var("COORDINATOR_NATS_URL"),
+        })
+    }
+
+    /// Overrides the settings of this layer by the ones of another.
+    #[cfg(feature = "std")]
+    fn merge(self, other: Layer) -> Layer {
+        Layer {
+            #[cfg(feature = "std")]
+            bind_addr: other.bind_addr.or(self.bind_addr),
+            base_path: other.base_path.or(self.base_path),
+            static_dir: other.static_dir.or(self.static_dir),
+            log: other.log.or(self.log),
+            ice_servers: other.ice_servers.or(self.ice_servers),
+            turn_secret: other.turn_secret.or(self.turn_secret),
+            max_participants: other.max_participants.or(self.max_participants),
+            resume_grace_period: other.resume_grace_period.or(self.resume_grace_period),
+            wait_timeout: other.wait_timeout.or(self.wait_timeout),
+            #[cfg(feature = "turn-server")]
+            turn_listen_addr: other.turn_listen_addr.or(self.turn_listen_addr),
+            #[cfg(feature = "turn-server")]
+            turn_public_ip: other.turn_public_ip.or(self.turn_public_ip),
+            #[cfg(feature = "tls")]
+            tls_cert: other.tls_cert.or(self.tls_cert),
+            #[cfg(feature = "tls")]
+            tls_key: other.tls_key.or(self.tls_key),
+            #[cfg(feature = "redis")]
+            redis_url: other.redis_url.or(self.redis_url),
+            #[cfg(feature = "nats")]
+            nats_url: other.nats_url.or(self.nats_url),
+        }
+    }
+}
+
+/// Parses a JSON array of ICE servers.
+fn parse_ice_servers(name: &'static str, servers: &str) -> Result<Vec<IceServer>, ConfigError> {
+    serde_json::from_str(servers).map_err(|error| {
+        ConfigError::Invalid(
+            name,
+            format!("should be a JSON array of ICE servers: {error}"),
+        )
+    })
+}
+
+/// Parses a variable, if it is set.
+fn parse_var<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|error| ConfigError::Invalid(name, format!("{value}: {error}")))
+        })
+        .transpose()
+}
+
+/// Configuration of the ICE servers handed to peers.
+#[derive(Debug, Clone)]
+pub struct IceConfig {
+    servers: Vec<IceServer>,
+    /// Secret shared with the TURN servers to mint ephemeral credentials,
+    /// following the TURN REST API scheme of coturn's `use-auth-secret`.
+    turn_secret: Option<String>,
+}
+
+impl IceConfig {
+    /// Creates the configuration from the servers handed to peers, and the
+    /// secret to mint TURN credentials with, if any.
+    pub fn new(servers: Vec<IceServer>, turn_secret: Option<String>) -> IceConfig {
+        IceConfig {
+            servers,
+            turn_secret,
+        }
+    }
+
+    /// Hands the embedded STUN and TURN server at its public address to
+    /// peers, before the other servers.
+    ///
+    /// Returns the TURN secret, which is generated if none is configured.
+    #[cfg(feature = "turn-server")]
+    pub(crate) fn embed_turn_server(&mut self, address: SocketAddr) -> String {
+        self.servers.insert(
+            0,
+            IceServer {
+                urls: vec![
+                    format!("stun:{address}"),
+                    format!("turn:{address}?transport=udp"),
+                    format!("turn:{address}?transport=tcp"),
+                ],
+                username: None,
+                credential: None,
+            },
+        );
+        self.turn_secret
+            .get_or_insert_with(|| Uuid::new_v4().simple().to_string())
+            .clone()
+    }
+
+    /// Returns the ICE servers for a new session.
+    ///
+    /// TURN servers configured without credentials get ephemeral ones when a
+    /// TURN secret is configured.
+    pub(crate) fn ice_servers(&self) -> Vec<IceServer> {
+        let Some(secret) = &self.turn_secret else {
+            return self.servers.clone();
+        };
+
+        let (username, credential) = turn_credential(secret, &Uuid::new_v4().simple().to_string());
+        self.servers
+            .iter()
+            .cloned()
+            .map(|mut server| {
+                let is_turn = server.urls.iter().any(|url| url.starts_with("turn"));
+                if is_turn && server.username.is_none() && server.credential.is_none() {
+                    server.username = Some(username.clone());
+                    server.credential = Some(credential.clone());
+                }
+                server
+            })
+            .collect()
+    }
+}
+
+/// Configuration of the embedded STUN and TURN server.
+#[cfg(feature = "turn-server")]
+#[derive(Debug, Clone)]
+pub struct TurnServerConfig {
+    /// Address listened on, both in UDP and TCP.
+    pub listen_addr: SocketAddr,
+    /// Public IP address, which peers reach the server and its relays on.
+    /// Required to run the server.
+    pub public_ip: Option<IpAddr>,
+}
+
+/// Certificate of the coordinator.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain.
+    pub cert: PathBuf,
+    /// PEM file of the private key.
+    pub key: PathBuf,
+}
+
+/// Mints a time-limited TURN username and password for a user.
+///
+/// The username is the expiry timestamp followed by the user.
+fn turn_credential(secret: &str, user: &str) -> (String, String) {
+    let expiry = (SystemTime::now() + TURN_CREDENTIAL_TTL)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let username = format!("{expiry}:{user}");
+    let password = turn_password(secret, &username);
+    (username, password)
+}
+
+/// Returns the TURN password of a username, that is the Base64 of the
+/// HMAC-SHA1 of the username with the secret.
+pub(crate) fn turn_password(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Settings valid on their own, whatever the features.
+    fn valid() -> Layer {
+        Layer {
+            static_dir: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../static")),
+            #[cfg(feature = "turn-server")]
+            turn_public_ip: Some(IpAddr::from([192, 0, 2, 1])),
+            ..Layer::default()
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Result<Layer, ConfigError> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        Layer::from_vars(|name| vars.get(name).cloned())
+    }
+
+    /// Returns the setting rejected in a layer on top of the valid settings.
+    #[cfg(feature = "std")]
+    fn invalid(layer: Layer) -> &'static str {
+        match Config::from_layer(valid().merge(layer)) {
+            Err(ConfigError::Invalid(setting, _)) => setting,
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::from_layer(valid()).unwrap();
+        assert_eq!(config.base_path, "/");
+        assert_eq!(config.max_participants, 6);
+        assert_eq!(config.resume_grace_period, Duration::from_secs(30));
+        assert_eq!(config.wait_timeout, Duration::from_secs(300));
+        assert_eq!(config.ice.servers[0].urls, [DEFAULT_STUN_SERVER]);
+        assert_eq!(config.ice.turn_secret, None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn file_is_overridden_by_env_then_cli() {
+        let file: Layer = toml::from_str(
+            r#"
+            base_path = "/file/"
+            log = "coordinator=info"
+            max_participants = 3
+            resume_grace_period = 10
+            "#,
+        )
+        .unwrap();
+        let env = Layer {
+            max_participants: Some(4),
+            ..vars(&[("RUST_LOG", "coordinator=debug")]).unwrap()
+        };
+        let cli = Layer {
+            max_participants: Some(5),
+            ..Layer::default()
+        };
+
+        let config = Config::from_layer(valid().merge(file).merge(env).merge(cli)).unwrap();
+        assert_eq!(config.base_path, "/file/");
+        assert_eq!(config.log, "coordinator=debug");
+        assert_eq!(config.max_participants, 5);
+        assert_eq!(config.resume_grace_period, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn vars_are_parsed() {
+        let layer = vars(&[
+            ("COORDINATOR_BASE_PATH", "/meet/"),
+            ("COORDINATOR_MAX_PARTICIPANTS", "2"),
+            ("COORDINATOR_WAIT_TIMEOUT", "60"),
+            ("COORDINATOR_TURN_SECRET", "secret"),
+            (
+                "COORDINATOR_ICE_SERVERS",
+                r#"[{"urls": ["turn:turn.example.com"], "username": "u", "credential": "p"}]"#,
+            ),
+        ])
+        .unwrap();
+        assert_eq!(layer.base_path.as_deref(), Some("/meet/"));
+        assert_eq!(layer.max_participants, Some(2));
+        assert_eq!(layer.wait_timeout, Some(60));
+        assert_eq!(layer.turn_secret.as_deref(), Some("secret"));
+        let servers = layer.ice_servers.unwrap();
+        assert_eq!(servers[0].urls, ["turn:turn.example.com"]);
+        assert_eq!(servers[0].username.as_deref(), Some("u"));
+        assert_eq!(servers[0].credential.as_deref(), Some("p"));
+        assert_eq!(layer.resume_grace_period, None);
+    }
+
+    #[test]
+    fn invalid_vars_are_rejected() {
+        for (name, value) in [
+            ("COORDINATOR_MAX_PARTICIPANTS", "many"),
+            ("COORDINATOR_RESUME_GRACE_PERIOD", "-1"),
+            ("COORDINATOR_ICE_SERVERS", "stun:stun.example.com"),
+        ] {
+            match vars(&[(name, value)]) {
+                Err(ConfigError::Invalid(setting, _)) => assert_eq!(setting, name),
+                result => panic!("{name}={value} is accepted: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn invalid_settings_are_rejected() {
+        let server = |url: Option<&str>| IceServer {
+            urls: url.into_iter().map(String::from).collect(),
+            username: None,
+            credential: None,
+        };
+        let cases = [
+            (
+                Layer {
+                    base_path: Some("meet/".into()),
+                    ..Layer::default()
+                },
+                "base_path",
+            ),
+            (
+                Layer {
+                    base_path: Some("/meet?".into()),
+                    ..Layer::default()
+                },
+                "base_path",
+            ),
+            (
+                Layer {
+                    static_dir: Some(PathBuf::from(file!())),
+                    ..Layer::default()
+                },
+                "static_dir",
+            ),
+            (
+                Layer {
+                    log: Some("coordinator=loud".into()),
+                    ..Layer::default()
+                },
+                "log",
+            ),
+            (
+                Layer {
+                    ice_servers: Some(vec![server(None)]),
+                    ..Layer::default()
+                },
+                "ice_servers",
+            ),
+            (
+                Layer {
+                    ice_servers: Some(vec![server(Some("https://stun.example.com"))]),
+                    ..Layer::default()
+                },
+                "ice_servers",
+            ),
+            (
+                Layer {
+                    turn_secret: Some("".into()),
+                    ..Layer::default()
+                },
+                "turn_secret",
+            ),
+            (
+                Layer {
+                    max_participants: Some(1),
+                    ..Layer::default()
+                },
+                "max_participants",
+            ),
+            (
+                Layer {
+                    wait_timeout: Some(0),
+                    ..Layer::default()
+                },
+                "wait_timeout",
+            ),
+        ];
+        for (layer, setting) in cases {
+            assert_eq!(invalid(layer), setting);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "turn-server")]
+    fn turn_server_needs_a_public_ip() {
+        let layer = Layer {
+            turn_public_ip: None,
+            ..valid()
+        };
+        assert!(matches!(
+            Config::from_layer(layer),
+            Err(ConfigError::Invalid("turn_public_ip", _))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_cert_and_key_go_together() {
+        let file = PathBuf::from(file!());
+        let cert = Layer {
+            tls_cert: Some(file.clone()),
+            ..Layer::default()
+        };
+        assert_eq!(invalid(cert), "tls_key");
+        let key = Layer {
+            tls_key: Some(file.clone()),
+            ..Layer::default()
+        };
+        assert_eq!(invalid(key), "tls_cert");
+        let missing = Layer {
+            tls_cert: Some(file.with_extension("pem")),
+            tls_key: Some(file),
+            ..Layer::default()
+        };
+        assert_eq!(invalid(missing), "tls_cert");
+    }
+
+    #[test]
+    #[cfg(feature = "redis")]
+    fn redis_url_is_checked() {
+        let layer = Layer {
+            redis_url: Some("http://localhost/".into()),
+            ..Layer::default()
+        };
+        assert_eq!(invalid(layer), "redis_url");
+    }
+
+    #[test]
+    #[cfg(feature = "nats")]
+    fn nats_url_is_checked() {
+        let layer = Layer {
+            nats_url: Some("nats://localhost:port".into()),
+            ..Layer::default()
+        };
+        assert_eq!(invalid(layer), "nats_url");
+        #[cfg(feature = "redis")]
+        {
+            let both = Layer {
+                nats_url: Some("nats://localhost:4222".into()),
+                redis_url: Some("redis://localhost/".into()),
+                ..Layer::default()
+            };
+            assert_eq!(invalid(both), "nats_url");
+        }
+    }
+
+    #[test]
+    fn turn_password_is_the_hmac_of_the_username() {
+        assert_eq!(
+            turn_password("secret", "1700000000:alice"),
+            "d8soP47RbdIKLDUOpnJPVQyq5Ts="
+        );
+    }
+
+    #[test]
+    fn turn_credential_expires_in_the_future() {
+        let (username, password) = turn_credential("secret", "alice");
+        let (expiry, user) = username.split_once(':').unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(expiry.parse::<u64>().unwrap() > now.as_secs());
+        assert_eq!(user, "alice");
+        assert_eq!(password, turn_password("secret", &username));
+    }
+
+    #[test]
+    fn only_turn_servers_without_credentials_get_minted_ones() {
+        let server = |url: &str, credentials: Option<(&str, &str)>| IceServer {
+            urls: vec![url.to_owned()],
+            username: credentials.map(|(username, _)| username.to_owned()),
+            credential: credentials.map(|(_, credential)| credential.to_owned()),
+        };
+        let servers = vec![
+            server("stun:stun.example.com", None),
+            server("turn:turn.example.com", None),
+            server("turns:turn.example.com", Some(("user", "password"))),
+        ];
+
+        let ice = IceConfig::new(servers.clone(), None);
+        assert_eq!(ice.ice_servers(), servers);
+
+        let ice = IceConfig::new(servers.clone(), Some("secret".into()));
+        let minted = ice.ice_servers();
+        assert_eq!(minted[0], servers[0]);
+        assert_eq!(minted[2], servers[2]);
+        let username = minted[1].username.as_deref().unwrap();
+        assert_eq!(
+            minted[1].credential.as_deref(),
+            Some(turn_password("secret", username).as_str())
+        );
+    }
+}
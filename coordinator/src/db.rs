@@ -1,108 +1,348 @@
-//! This module is a mimicry of Redis db with only limited while necessary commands.
-//! Most code is excerpted from https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs.
+//! Storage of the meetings, behind the [`Storage`] trait so that coordinators
+//! may share it in another backend.
+//!
+//! The default backend, [`Db`], is a mimicry of Redis db with only limited while
+//! necessary commands. Most code is excerpted from
+//! https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs.
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
+    error::Error,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use tokio::sync::broadcast;
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Notify,
+    },
+    time::{self, Instant},
+};
+use tracing::debug;
 
-#[derive(Debug)]
-pub struct DbHolder {
-    db: Db,
+#[cfg(feature = "nats")]
+pub use self::nats::NatsDb;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisDb;
+
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "redis")]
+mod redis;
+
+/// Error of a storage backend, such as a lost connection.
+pub type StorageError = Box<dyn Error + Send + Sync>;
+
+/// Messages published to a channel after subscribing to it.
+pub type Subscription = BoxStream<'static, String>;
+
+/// Key-value store along with pub/sub channels, like a subset of Redis. Values
+/// are updated optimistically, by comparing their revision.
+#[async_trait]
+pub trait Storage: Send + Sync + 'static {
+    /// Delete the value associated with a key.
+    ///
+    /// Returns the number of value deleted, which may be 1 or 0.
+    async fn delete(&self, key: &str) -> Result<usize, StorageError>;
+
+    /// Delete the pubsub channel associated with a key, if the backend keeps
+    /// any state about it.
+    ///
+    /// Returns the number of pubsub channel deleted, which may be 1 or 0.
+    async fn delete_channel(&self, key: &str) -> Result<usize, StorageError>;
+
+    /// Get the value associated with a key, like `GET`, along with its
+    /// revision, which changes along with the value.
+    async fn get(&self, key: &str) -> Result<Option<(String, u64)>, StorageError>;
+
+    /// Set the value associated with a key, or delete it for `None`, only if
+    /// it is still at the given revision, or still missing for `None`. The time
+    /// to live of the key is kept.
+    ///
+    /// Returns whether the value was set, or else it changed in the meantime.
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        value: Option<String>,
+        revision: Option<u64>,
+    ) -> Result<bool, StorageError>;
+
+    /// Set or refresh the time to live of a key, like `EXPIRE`. The key is
+    /// deleted once it expires, unless refreshed in the meantime.
+    ///
+    /// Returns whether the key exists.
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, StorageError>;
+
+    /// Set or refresh the time to live of the pubsub channel associated with a
+    /// key, if the backend keeps any state about it. The channel is deleted
+    /// once it expires, unless refreshed in the meantime.
+    ///
+    /// Returns whether the channel exists.
+    async fn expire_channel(&self, key: &str, ttl: Duration) -> Result<bool, StorageError>;
+
+    /// Returns the messages of the requested channel, published by `PUBLISH`
+    /// commands from then on.
+    async fn subscribe(&self, key: &str) -> Result<Subscription, StorageError>;
+
+    /// Publish a message to the channel. Returns the number of subscribers
+    /// listening on the channel, when the backend can tell.
+    async fn publish(&self, key: &str, value: String) -> Result<usize, StorageError>;
+}
+
+pub(crate) struct DbHolder {
+    db: Arc<dyn Storage>,
 }
 
+/// In-memory storage of the meetings, which may be shared between several
+/// routers of the same process. Clones share the same storage.
+///
+/// Expired keys and channels are purged by a background task, spawned along
+/// with the first expiration, and shut down when the last clone is dropped.
 #[derive(Debug, Clone)]
-pub(crate) struct Db {
+pub struct Db {
     shared: Arc<Shared>,
+    _guard: Arc<DropGuard>,
 }
 
 #[derive(Debug)]
 struct Shared {
     state: Mutex<State>,
+
+    /// Notifies the background task handling entry expiration. The background
+    /// task waits on this to be notified, then checks for expired values or the
+    /// shutdown signal.
+    background_task: Notify,
 }
 
 #[derive(Debug)]
 struct State {
     entries: HashMap<String, Entry>,
-    pub_sub: HashMap<String, broadcast::Sender<String>>,
+    pub_sub: HashMap<String, Channel>,
+
+    /// Tracks key TTLs.
+    ///
+    /// A `BTreeSet` is used to maintain expirations sorted by when they expire.
+    /// This allows the background task to iterate this map to find the value
+    /// expiring next.
+    ///
+    /// While highly unlikely, it is possible for more than one expiration to be
+    /// created for the same instant. Because of this, the `Instant` is
+    /// insufficient for the key. A unique key (`Expiring`) is used to
+    /// break these ties.
+    expirations: BTreeSet<(Instant, Expiring)>,
+
+    /// Revision of the last value set.
+    revision: u64,
+
+    /// Whether the background task was spawned.
+    purging: bool,
+
+    /// True when the Db instance is shutting down. This happens when all `Db`
+    /// values drop. Setting this to `true` signals to the background task to
+    /// exit.
+    shutdown: bool,
 }
 
 /// Entry in the key-value store
 #[derive(Debug)]
 struct Entry {
     /// Stored data
-    data: u8,
+    data: String,
+
+    /// Revision at which the data was set.
+    revision: u64,
+
+    /// Instant at which the entry expires and should be removed from the
+    /// database.
+    expires_at: Option<Instant>,
+}
+
+/// Pubsub channel
+#[derive(Debug)]
+struct Channel {
+    sender: broadcast::Sender<String>,
+
+    /// Instant at which the channel expires and should be removed from the
+    /// database, which ends its subscriptions.
+    expires_at: Option<Instant>,
+}
+
+/// Key or channel with a time to live.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Expiring {
+    Entry(String),
+    Channel(String),
+}
+
+/// Shuts the background task down when the last `Db` clone is dropped.
+#[derive(Debug)]
+struct DropGuard {
+    shared: Arc<Shared>,
 }
 
 impl DbHolder {
-    pub(crate) fn new() -> DbHolder {
-        DbHolder { db: Db::new() }
+    pub(crate) fn new(db: Arc<dyn Storage>) -> DbHolder {
+        DbHolder { db }
     }
 
     /// Get the shared database. Internally, this is an
     /// `Arc`, so a clone only increments the ref count.
-    pub(crate) fn db(&self) -> Db {
+    pub(crate) fn db(&self) -> Arc<dyn Storage> {
         self.db.clone()
     }
 }
 
 impl Db {
-    pub(crate) fn new() -> Db {
+    pub fn new() -> Db {
         let shared = Arc::new(Shared {
             state: Mutex::new(State {
                 entries: HashMap::new(),
                 pub_sub: HashMap::new(),
+                expirations: BTreeSet::new(),
+                revision: 0,
+                purging: false,
+                shutdown: false,
             }),
+            background_task: Notify::new(),
         });
 
-        Db { shared }
+        Db {
+            _guard: Arc::new(DropGuard {
+                shared: shared.clone(),
+            }),
+            shared,
+        }
     }
 
-    /// Delete the value associated with a key.
+    /// Sets the expiration of a key or a channel, and spawns the background
+    /// task or wakes it up if needed.
     ///
-    /// Returns the number of value deleted, which may be 1 or 0.
-    pub(crate) fn delete(&self, key: &str) -> usize {
+    /// Returns whether the key or the channel exists.
+    fn set_expiration(&self, expiring: Expiring, ttl: Duration) -> bool {
         let mut state = self.shared.state.lock().unwrap();
-        state.entries.remove(key).map(|_| 1).unwrap_or(0)
-    }
+        let when = Instant::now() + ttl;
 
-    /// Delete the pubsub channel associated with a key.
-    ///
-    /// Returns the number of pubsub channel deleted, which may be 1 or 0.
-    pub(crate) fn delete_channel(&self, key: &str) -> usize {
-        let mut state = self.shared.state.lock().unwrap();
-        state.pub_sub.remove(key).map(|_| 1).unwrap_or(0)
-    }
+        let expires_at = match &expiring {
+            Expiring::Entry(key) => state.entries.get_mut(key).map(|e| &mut e.expires_at),
+            Expiring::Channel(key) => state.pub_sub.get_mut(key).map(|c| &mut c.expires_at),
+        };
+        let Some(expires_at) = expires_at else {
+            return false;
+        };
+        let previous = expires_at.replace(when);
 
-    /// Set only the key without value.
-    ///
-    /// Returns the number a key is set.
-    pub(crate) fn set(&self, key: String) -> u8 {
-        let mut state = self.shared.state.lock().unwrap();
-        if let Some(v) = state.entries.get_mut(&key) {
-            // Increment the value counter every the same key is set.
-            v.data += 1;
-            return v.data;
+        // Only notify the background task if the newly inserted expiration is
+        // the **next** key to evict. In this case, the worker needs to be woken
+        // up to update its state.
+        let notify = state
+            .next_expiration()
+            .map(|expiration| expiration > when)
+            .unwrap_or(true);
+
+        // If the key or the channel already had an expiration, the stale
+        // entry of the `expirations` map is removed. This avoids leaking data.
+        if let Some(previous) = previous {
+            state.expirations.remove(&(previous, expiring.clone()));
         }
+        state.expirations.insert((when, expiring));
 
-        // Insert the entry into the `HashMap`. Set value counter to 1 for the first time.
-        state.entries.insert(key, Entry { data: 1 });
+        if !state.purging {
+            state.purging = true;
+            tokio::spawn(purge_expired_tasks(self.shared.clone()));
+        }
 
         // Release the mutex before notifying the background task. This helps
         // reduce contention by avoiding the background task waking up only to
         // be unable to acquire the mutex due to this function still holding it.
         drop(state);
 
-        1
+        if notify {
+            // Finally, only notify the background task if it needs to update
+            // its state to reflect a new expiration.
+            self.shared.background_task.notify_one();
+        }
+
+        true
     }
+}
 
-    /// Returns a `Receiver` for the requested channel.
-    ///
-    /// The returned `Receiver` is used to receive values broadcast by `PUBLISH`
-    /// commands.
-    pub(crate) fn subscribe(&self, key: String) -> broadcast::Receiver<String> {
+#[async_trait]
+impl Storage for Db {
+    async fn delete(&self, key: &str) -> Result<usize, StorageError> {
+        let mut state = self.shared.state.lock().unwrap();
+        Ok(state.remove_entry(key) as usize)
+    }
+
+    async fn delete_channel(&self, key: &str) -> Result<usize, StorageError> {
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(channel) = state.pub_sub.remove(key) else {
+            return Ok(0);
+        };
+        if let Some(when) = channel.expires_at {
+            state
+                .expirations
+                .remove(&(when, Expiring::Channel(key.to_owned())));
+        }
+        Ok(1)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(String, u64)>, StorageError> {
+        let state = self.shared.state.lock().unwrap();
+        Ok(state
+            .entries
+            .get(key)
+            .map(|entry| (entry.data.clone(), entry.revision)))
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        value: Option<String>,
+        revision: Option<u64>,
+    ) -> Result<bool, StorageError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.entries.get(key).map(|entry| entry.revision) != revision {
+            return Ok(false);
+        }
+
+        let Some(data) = value else {
+            state.remove_entry(key);
+            return Ok(true);
+        };
+        state.revision += 1;
+        let revision = state.revision;
+        match state.entries.get_mut(key) {
+            // The expiration is kept, as with `SET KEEPTTL` of Redis.
+            Some(entry) => {
+                entry.data = data;
+                entry.revision = revision;
+            }
+            None => {
+                state.entries.insert(
+                    key.to_owned(),
+                    Entry {
+                        data,
+                        revision,
+                        expires_at: None,
+                    },
+                );
+            }
+        }
+        Ok(true)
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, StorageError> {
+        Ok(self.set_expiration(Expiring::Entry(key.to_owned()), ttl))
+    }
+
+    async fn expire_channel(&self, key: &str, ttl: Duration) -> Result<bool, StorageError> {
+        Ok(self.set_expiration(Expiring::Channel(key.to_owned()), ttl))
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Subscription, StorageError> {
         use std::collections::hash_map::Entry;
 
         // Acquire the mutex
@@ -111,8 +351,8 @@ impl Db {
         // If there is no entry for the requested channel, then create a new
         // broadcast channel and associate it with the key. If one already
         // exists, return an associated receiver.
-        match state.pub_sub.entry(key) {
-            Entry::Occupied(e) => e.get().subscribe(),
+        let rx = match state.pub_sub.entry(key.to_owned()) {
+            Entry::Occupied(e) => e.get().sender.subscribe(),
             Entry::Vacant(e) => {
                 // No broadcast channel exists yet, so create one.
                 //
@@ -125,26 +365,296 @@ impl Db {
                 // in old messages being dropped. This prevents slow consumers
                 // from blocking the entire system.
                 let (tx, rx) = broadcast::channel(10);
-                e.insert(tx);
+                e.insert(Channel {
+                    sender: tx,
+                    expires_at: None,
+                });
                 rx
             }
-        }
+        };
+
+        // Skip the messages dropped for a slow subscriber, and end along with
+        // the channel.
+        Ok(stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) => return Some((msg, rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed())
     }
 
-    /// Publish a message to the channel. Returns the number of subscribers
-    /// listening on the channel.
-    pub(crate) fn publish(&self, key: &str, value: String) -> usize {
+    async fn publish(&self, key: &str, value: String) -> Result<usize, StorageError> {
         let state = self.shared.state.lock().unwrap();
 
-        state
+        Ok(state
             .pub_sub
             .get(key)
             // On a successful message send on the broadcast channel, the number
             // of subscribers is returned. An error indicates there are no
             // receivers, in which case, `0` should be returned.
-            .map(|tx| tx.send(value).unwrap_or(0))
+            .map(|channel| channel.sender.send(value).unwrap_or(0))
             // If there is no entry for the channel key, then there are no
             // subscribers. In this case, return `0`.
-            .unwrap_or(0)
+            .unwrap_or(0))
+    }
+}
+
+impl Default for Db {
+    fn default() -> Self {
+        Db::new()
+    }
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        // Signal the `Db` instance to shut down the task that purges expired keys
+        self.shared.shutdown_purge_task();
+    }
+}
+
+impl Shared {
+    /// Purge all expired keys and return the `Instant` at which the **next**
+    /// key will expire. The background task will sleep until this instant.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.shutdown {
+            // The database is shutting down. All handles to the shared state
+            // have dropped. The background task should exit.
+            return None;
+        }
+
+        // This is needed to make the borrow checker happy. In short, `lock()`
+        // returns a `MutexGuard` and not a `&mut State`. The borrow checker is
+        // not able to see "through" the mutex guard and determine that it is
+        // safe to access both `state.expirations` and `state.entries` mutably,
+        // so we get a "real" mutable reference to `State` outside of the loop.
+        let state = &mut *state;
+
+        // Find all keys scheduled to expire **before** now.
+        let now = Instant::now();
+
+        while let Some((when, expiring)) = state.expirations.first().cloned() {
+            if when > now {
+                // Done purging, `when` is the instant at which the next key
+                // expires. The worker task will wait until this instant.
+                return Some(when);
+            }
+
+            // The key expired, remove it
+            match &expiring {
+                Expiring::Entry(key) => {
+                    debug!("Key {key} expired.");
+                    state.entries.remove(key);
+                }
+                // Dropping the sender ends the subscriptions.
+                Expiring::Channel(key) => {
+                    debug!("Channel {key} expired.");
+                    state.pub_sub.remove(key);
+                }
+            }
+            state.expirations.remove(&(when, expiring));
+        }
+
+        None
+    }
+
+    /// Returns `true` if the database is shutting down
+    ///
+    /// The `shutdown` flag is set when all `Db` values have dropped, indicating
+    /// that the shared state can no longer be accessed.
+    fn is_shutdown(&self) -> bool {
+        self.state.lock().unwrap().shutdown
+    }
+
+    /// Signals the purge background task to shut down.
+    fn shutdown_purge_task(&self) {
+        // The background task must be signaled to shut down. This is done by
+        // setting `State::shutdown` to `true` and signalling the task.
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+
+        // Drop the lock before signalling the background task. This helps
+        // reduce lock contention by ensuring the background task doesn't
+        // wake up only to be unable to acquire the mutex.
+        drop(state);
+        self.background_task.notify_one();
+    }
+}
+
+impl State {
+    /// Removes an entry along with its expiration. Returns whether it existed.
+    fn remove_entry(&mut self, key: &str) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        if let Some(when) = entry.expires_at {
+            self.expirations
+                .remove(&(when, Expiring::Entry(key.to_owned())));
+        }
+        true
+    }
+
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.first().map(|expiration| expiration.0)
+    }
+}
+
+/// Routine executed by the background task.
+///
+/// Wait to be notified. On notification, purge any expired keys from the shared
+/// state handle. If `shutdown` is set, terminate the task.
+async fn purge_expired_tasks(shared: Arc<Shared>) {
+    // If the shutdown flag is set, then the task should exit.
+    while !shared.is_shutdown() {
+        // Purge all keys that are expired. The function returns the instant at
+        // which the **next** key will expire. The worker should wait until the
+        // instant has passed then purge again.
+        if let Some(when) = shared.purge_expired_keys() {
+            // Wait until the next key expires **or** until the background task
+            // is notified. If the task is notified, then it must reload its
+            // state as new keys have been set to expire early. This is done by
+            // looping.
+            tokio::select! {
+                _ = time::sleep_until(when) => {}
+                _ = shared.background_task.notified() => {}
+            }
+        } else {
+            // There are no keys expiring in the future. Wait until the task is
+            // notified.
+            shared.background_task.notified().await;
+        }
+    }
+
+    debug!("Purge background task shut down.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn set(db: &Db, key: &str) {
+        let revision = db.get(key).await.unwrap().map(|(_, revision)| revision);
+        assert!(db
+            .compare_and_set(key, Some("value".into()), revision)
+            .await
+            .unwrap());
+    }
+
+    async fn exists(db: &Db, key: &str) -> bool {
+        db.get(key).await.unwrap().is_some()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_expire_unless_refreshed() {
+        let db = Db::new();
+        set(&db, "key").await;
+        assert!(db.expire("key", Duration::from_secs(10)).await.unwrap());
+        assert!(!db.expire("missing", Duration::from_secs(10)).await.unwrap());
+
+        time::sleep(Duration::from_secs(6)).await;
+        assert!(db.expire("key", Duration::from_secs(10)).await.unwrap());
+        time::sleep(Duration::from_secs(6)).await;
+        assert!(exists(&db, "key").await);
+        time::sleep(Duration::from_secs(5)).await;
+        assert!(!exists(&db, "key").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_are_purged_in_order() {
+        let db = Db::new();
+        for key in ["a", "b", "c"] {
+            set(&db, key).await;
+        }
+        db.expire("b", Duration::from_secs(20)).await.unwrap();
+        db.expire("a", Duration::from_secs(10)).await.unwrap();
+        // An earlier expiration wakes the background task up.
+        db.expire("c", Duration::from_secs(60)).await.unwrap();
+        db.expire("c", Duration::from_secs(5)).await.unwrap();
+
+        time::sleep(Duration::from_secs(6)).await;
+        assert!(exists(&db, "a").await);
+        assert!(exists(&db, "b").await);
+        assert!(!exists(&db, "c").await);
+        time::sleep(Duration::from_secs(5)).await;
+        assert!(!exists(&db, "a").await);
+        assert!(exists(&db, "b").await);
+        time::sleep(Duration::from_secs(10)).await;
+        assert!(!exists(&db, "b").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn updates_keep_the_time_to_live() {
+        let db = Db::new();
+        set(&db, "key").await;
+        db.expire("key", Duration::from_secs(10)).await.unwrap();
+        time::sleep(Duration::from_secs(5)).await;
+        set(&db, "key").await;
+        time::sleep(Duration::from_secs(6)).await;
+        assert!(!exists(&db, "key").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deleted_keys_forget_their_expiration() {
+        let db = Db::new();
+        set(&db, "key").await;
+        db.expire("key", Duration::from_secs(10)).await.unwrap();
+        assert_eq!(db.delete("key").await.unwrap(), 1);
+        assert_eq!(db.delete("key").await.unwrap(), 0);
+
+        set(&db, "key").await;
+        time::sleep(Duration::from_secs(11)).await;
+        assert!(exists(&db, "key").await);
+
+        // Removed by a compare-and-set as well.
+        db.expire("key", Duration::from_secs(10)).await.unwrap();
+        let (_, revision) = db.get("key").await.unwrap().unwrap();
+        assert!(db
+            .compare_and_set("key", None, Some(revision))
+            .await
+            .unwrap());
+        set(&db, "key").await;
+        time::sleep(Duration::from_secs(11)).await;
+        assert!(exists(&db, "key").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_channels_end_their_subscriptions() {
+        let db = Db::new();
+        let mut subscription = db.subscribe("channel").await.unwrap();
+        assert!(db
+            .expire_channel("channel", Duration::from_secs(10))
+            .await
+            .unwrap());
+        assert!(!db
+            .expire_channel("missing", Duration::from_secs(10))
+            .await
+            .unwrap());
+        assert_eq!(db.publish("channel", "hello".into()).await.unwrap(), 1);
+
+        time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(subscription.next().await.as_deref(), Some("hello"));
+        assert_eq!(subscription.next().await, None);
+        assert_eq!(db.publish("channel", "hello".into()).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deleted_channels_forget_their_expiration() {
+        let db = Db::new();
+        let mut subscription = db.subscribe("channel").await.unwrap();
+        db.expire_channel("channel", Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(db.delete_channel("channel").await.unwrap(), 1);
+        assert_eq!(subscription.next().await, None);
+        assert_eq!(db.delete_channel("channel").await.unwrap(), 0);
+
+        let _subscription = db.subscribe("channel").await.unwrap();
+        time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(db.publish("channel", "hello".into()).await.unwrap(), 1);
     }
 }
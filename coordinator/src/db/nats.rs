@@ -0,0 +1,167 @@
+//! Storage in NATS, so that several coordinators share the same meetings.
+//!
+//! Values are kept in a JetStream key-value bucket, with the sequence numbers
+//! of the stream as revisions. Its entries all expire after the same time to
+//! live, once they were last written or refreshed. Channels are NATS subjects.
+//! Keys and channels are encoded in base64, since passphrases may contain
+//! characters that are not allowed in subjects.
+
+use std::time::Duration;
+
+use async_nats::{
+    jetstream::{
+        self,
+        kv::{self, CreateErrorKind, Operation, UpdateErrorKind},
+    },
+    Client,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::StreamExt;
+use tracing::warn;
+
+use super::{Storage, StorageError, Subscription};
+
+/// Name of the key-value bucket, and prefix of every subject.
+const BUCKET: &str = "meeting";
+
+/// Storage of the meetings in NATS. Clones share the same connection.
+#[derive(Debug, Clone)]
+pub struct NatsDb {
+    client: Client,
+    kv: kv::Store,
+}
+
+impl NatsDb {
+    /// Connects to the NATS server at a URL, such as `nats://127.0.0.1:4222`,
+    /// and creates or updates the key-value bucket. JetStream should be
+    /// enabled on the server.
+    ///
+    /// Values are kept for `ttl` after they were last written or refreshed,
+    /// whatever the time to live given to [`Storage::expire`], so coordinators
+    /// sharing the bucket should give the same one.
+    pub async fn connect(url: &str, ttl: Duration) -> Result<NatsDb, StorageError> {
+        let client = async_nats::connect(url).await?;
+        let kv = jetstream::new(client.clone())
+            .create_or_update_key_value(kv::Config {
+                bucket: BUCKET.into(),
+                history: 1,
+                max_age: ttl,
+                ..Default::default()
+            })
+            .await?;
+        Ok(NatsDb { client, kv })
+    }
+
+    /// Returns the value of an encoded key and its revision, if it is set.
+    async fn entry(&self, key: &str) -> Result<Option<(String, u64)>, StorageError> {
+        match self.kv.entry(key).await? {
+            Some(entry) if entry.operation == Operation::Put => {
+                let value = String::from_utf8(entry.value.into())?;
+                Ok(Some((value, entry.revision)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for NatsDb {
+    async fn delete(&self, key: &str) -> Result<usize, StorageError> {
+        let key = encode(key);
+        if self.entry(&key).await?.is_none() {
+            return Ok(0);
+        }
+        self.kv.purge(&key).await?;
+        Ok(1)
+    }
+
+    /// NATS subjects hold no state, and are unsubscribed from along with their
+    /// subscribers.
+    async fn delete_channel(&self, _key: &str) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(String, u64)>, StorageError> {
+        self.entry(&encode(key)).await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        value: Option<String>,
+        revision: Option<u64>,
+    ) -> Result<bool, StorageError> {
+        let key = encode(key);
+        let result = match (value, revision) {
+            (Some(value), Some(revision)) => self.kv.update(&key, value.into(), revision).await,
+            (Some(value), None) => match self.kv.create(&key, value.into()).await {
+                Err(error) if error.kind() == CreateErrorKind::AlreadyExists => return Ok(false),
+                result => return result.map(|_| true).map_err(Into::into),
+            },
+            (None, Some(revision)) => self
+                .kv
+                .delete_expect_revision(&key, Some(revision))
+                .await
+                .map(|_| revision),
+            (None, None) => return Ok(self.entry(&key).await?.is_none()),
+        };
+        match result {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == UpdateErrorKind::WrongLastRevision => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// The entries of the bucket all have the time to live given when
+    /// connecting, which is restarted by writing the value again.
+    async fn expire(&self, key: &str, _ttl: Duration) -> Result<bool, StorageError> {
+        let key = encode(key);
+        let Some((value, revision)) = self.entry(&key).await? else {
+            return Ok(false);
+        };
+        match self.kv.update(&key, value.into(), revision).await {
+            // Changed in the meantime, so refreshed anyway.
+            Err(error) if error.kind() == UpdateErrorKind::WrongLastRevision => Ok(true),
+            result => result.map(|_| true).map_err(Into::into),
+        }
+    }
+
+    async fn expire_channel(&self, _key: &str, _ttl: Duration) -> Result<bool, StorageError> {
+        Ok(false)
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Subscription, StorageError> {
+        let subscriber = self.client.subscribe(subject(key)).await?;
+        // Make sure that the server knows about the subscription before
+        // anything is published to it.
+        self.client.flush().await?;
+
+        let key = key.to_owned();
+        Ok(subscriber
+            .filter_map(move |msg| {
+                let payload = String::from_utf8(msg.payload.into());
+                if let Err(error) = &payload {
+                    warn!("Received an invalid message on {key}: {error}");
+                }
+                async move { payload.ok() }
+            })
+            .boxed())
+    }
+
+    /// NATS does not tell how many subscribers received a message, so 1 is
+    /// returned once it is published.
+    async fn publish(&self, key: &str, value: String) -> Result<usize, StorageError> {
+        self.client.publish(subject(key), value.into()).await?;
+        Ok(1)
+    }
+}
+
+/// Encodes a key into the characters allowed in NATS subjects and keys.
+fn encode(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+fn subject(key: &str) -> String {
+    format!("{BUCKET}.{}", encode(key))
+}
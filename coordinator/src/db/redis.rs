@@ -0,0 +1,254 @@
+//! Storage in Redis, so that several coordinators behind a load balancer
+//! share the same meetings.
+//!
+//! Values are Redis hashes of the value and its revision, and expire along
+//! with their TTL in Redis. They are compared and set by a Lua script, and
+//! their revisions are drawn from a single counter, so that a value deleted
+//! and set again never gets a revision back. Channels are Redis channels,
+//! which every coordinator subscribes to once, however many participants
+//! listen to them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_util::{
+    stream::{self, StreamExt},
+    Stream,
+};
+use redis::{
+    aio::{ConnectionManager, PubSubSink, PubSubStream},
+    AsyncCommands, Client, Script,
+};
+use tokio::sync::{broadcast, Mutex};
+use tracing::warn;
+
+use super::{Storage, StorageError, Subscription};
+
+/// Prefix of every key and channel, to share a Redis instance with other
+/// applications.
+const PREFIX: &str = "meeting:";
+
+/// Counter of the revisions of every value, out of the keys of values.
+const REVISION: &str = "meeting-revision";
+
+/// Sets the value of `KEYS[1]` to `ARGV[2]`, or deletes it when missing, if its
+/// revision is still `ARGV[1]`, or if it is still missing for an empty one.
+static COMPARE_AND_SET: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local revision = redis.call('HGET', KEYS[1], 'revision')
+        if revision ~= (ARGV[1] ~= '' and ARGV[1] or false) then
+            return 0
+        end
+        if ARGV[2] == nil then
+            redis.call('DEL', KEYS[1])
+        else
+            redis.call('HSET', KEYS[1], 'value', ARGV[2], 'revision', redis.call('INCR', KEYS[2]))
+        end
+        return 1
+        ",
+    )
+});
+
+/// Storage of the meetings in Redis. Clones share the same connections.
+#[derive(Clone)]
+pub struct RedisDb {
+    client: Client,
+    /// Connection of the commands, which reconnects on failure.
+    conn: ConnectionManager,
+    pub_sub: Arc<Mutex<PubSub>>,
+}
+
+/// Connection dedicated to the channels, along with the local subscribers of
+/// every channel.
+#[derive(Default)]
+struct PubSub {
+    /// Reconnected on the next subscription when lost.
+    sink: Option<PubSubSink>,
+    channels: HashMap<String, broadcast::Sender<String>>,
+}
+
+impl RedisDb {
+    /// Connects to the Redis server at a URL, such as `redis://127.0.0.1/`.
+    pub async fn connect(url: &str) -> Result<RedisDb, StorageError> {
+        let client = Client::open(url)?;
+        let conn = client.get_connection_manager().await?;
+        Ok(RedisDb {
+            client,
+            conn,
+            pub_sub: Arc::default(),
+        })
+    }
+
+    /// Forwards the messages of Redis to the local subscribers of their
+    /// channel. Once the connection is lost, the local subscriptions end.
+    async fn dispatch(pub_sub: Arc<Mutex<PubSub>>, mut stream: PubSubStream) {
+        while let Some(msg) = stream.next().await {
+            let Some(channel) = msg.get_channel_name().strip_prefix(PREFIX) else {
+                continue;
+            };
+            match msg.get_payload::<String>() {
+                Ok(payload) => {
+                    if let Some(tx) = pub_sub.lock().await.channels.get(channel) {
+                        let _ = tx.send(payload);
+                    }
+                }
+                Err(error) => warn!("Received an invalid message on {channel}: {error}"),
+            }
+        }
+        warn!("Lost the Redis connection of the channels.");
+        let mut pub_sub = pub_sub.lock().await;
+        pub_sub.sink = None;
+        pub_sub.channels.clear();
+    }
+
+    /// Unsubscribes from a channel once it has no local subscriber left.
+    async fn unsubscribe(pub_sub: Arc<Mutex<PubSub>>, key: String) {
+        let mut pub_sub = pub_sub.lock().await;
+        if pub_sub
+            .channels
+            .get(&key)
+            .is_none_or(|tx| tx.receiver_count() > 0)
+        {
+            return;
+        }
+        pub_sub.channels.remove(&key);
+        if let Some(sink) = &mut pub_sub.sink {
+            if let Err(error) = sink.unsubscribe(prefixed(&key)).await {
+                warn!("Failed to unsubscribe from {key}: {error}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RedisDb {
+    async fn delete(&self, key: &str) -> Result<usize, StorageError> {
+        Ok(self.conn.clone().del(prefixed(key)).await?)
+    }
+
+    /// Redis channels hold no state, and are unsubscribed from along with
+    /// their last local subscriber.
+    async fn delete_channel(&self, _key: &str) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(String, u64)>, StorageError> {
+        let (value, revision): (Option<String>, Option<u64>) = self
+            .conn
+            .clone()
+            .hget(prefixed(key), &["value", "revision"])
+            .await?;
+        Ok(value.zip(revision))
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        value: Option<String>,
+        revision: Option<u64>,
+    ) -> Result<bool, StorageError> {
+        let mut invocation = COMPARE_AND_SET.key(prefixed(key));
+        invocation.key(REVISION).arg(
+            revision
+                .map(|revision| revision.to_string())
+                .unwrap_or_default(),
+        );
+        if let Some(value) = value {
+            invocation.arg(value);
+        }
+        Ok(invocation.invoke_async(&mut self.conn.clone()).await?)
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, StorageError> {
+        Ok(self
+            .conn
+            .clone()
+            .expire(prefixed(key), ttl.as_secs().max(1) as i64)
+            .await?)
+    }
+
+    async fn expire_channel(&self, _key: &str, _ttl: Duration) -> Result<bool, StorageError> {
+        Ok(false)
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Subscription, StorageError> {
+        let mut pub_sub = self.pub_sub.lock().await;
+        let rx = match pub_sub.channels.get(key) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let sink = match &mut pub_sub.sink {
+                    Some(sink) => sink,
+                    None => {
+                        let (sink, stream) = self.client.get_async_pubsub().await?.split();
+                        tokio::spawn(RedisDb::dispatch(self.pub_sub.clone(), stream));
+                        pub_sub.sink.insert(sink)
+                    }
+                };
+                sink.subscribe(prefixed(key)).await?;
+                let (tx, rx) = broadcast::channel(10);
+                pub_sub.channels.insert(key.to_owned(), tx);
+                rx
+            }
+        };
+
+        let guard = Unsubscribe {
+            pub_sub: self.pub_sub.clone(),
+            key: key.to_owned(),
+        };
+        Ok(receive(rx, guard).boxed())
+    }
+
+    async fn publish(&self, key: &str, value: String) -> Result<usize, StorageError> {
+        Ok(self.conn.clone().publish(prefixed(key), value).await?)
+    }
+}
+
+impl std::fmt::Debug for RedisDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisDb")
+            .field("client", &self.client)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Unsubscribes from a channel when dropped along with its last local
+/// subscriber. The check happens in a task, once the receiver is dropped too.
+struct Unsubscribe {
+    pub_sub: Arc<Mutex<PubSub>>,
+    key: String,
+}
+
+impl Drop for Unsubscribe {
+    fn drop(&mut self) {
+        let pub_sub = self.pub_sub.clone();
+        let key = std::mem::take(&mut self.key);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(RedisDb::unsubscribe(pub_sub, key));
+        }
+    }
+}
+
+/// Streams the messages of a local subscription, skipping the messages dropped
+/// for a slow subscriber.
+fn receive(rx: broadcast::Receiver<String>, guard: Unsubscribe) -> impl Stream<Item = String> {
+    use broadcast::error::RecvError;
+
+    stream::unfold((rx, guard), |(mut rx, guard)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => return Some((msg, (rx, guard))),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+fn prefixed(key: &str) -> String {
+    format!("{PREFIX}{key}")
+}
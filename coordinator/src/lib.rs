@@ -0,0 +1,24 @@
+//! Signaling server of Meeting.rs, which relays the WebRTC negotiation of
+//! peers in a meeting, and serves their web page.
+//!
+//! The server is built by [`MeetingServer`] into an axum [`Router`], which
+//! may be served on its own, as the `coordinator` executable does, or be
+//! nested into another service.
+//!
+//! [`Router`]: axum::Router
+
+pub mod config;
+pub mod db;
+mod room;
+mod router;
+mod server;
+mod session;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "turn-server")]
+pub mod turn_server;
+
+pub use config::Config;
+pub use db::Db;
+pub use protocol;
+pub use server::MeetingServer;
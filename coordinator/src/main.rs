@@ -1,42 +1,76 @@
-mod db;
-mod router;
-
 #[cfg(feature = "std")]
 use tracing::debug;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use router::route;
+use coordinator::{Config, MeetingServer};
 
 #[cfg(feature = "std")]
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "coordinator=trace".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let config = Config::load().unwrap_or_else(|error| {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    });
+    init_tracing(&config);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(config.bind_addr)
+        .await
+        .unwrap();
     debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, route())
-        .with_graceful_shutdown(shutdown_signal())
+    #[cfg(feature = "turn-server")]
+    let config = coordinator::turn_server::spawn(config)
+        .await
+        .unwrap_or_else(|error| {
+            eprintln!("error: cannot start the TURN server: {error}");
+            std::process::exit(1);
+        });
+
+    #[cfg(feature = "tls")]
+    let tls = config.tls.clone();
+    let server = MeetingServer::connect(config)
+        .await
+        .unwrap_or_else(|error| {
+            eprintln!("error: cannot connect to the storage: {error}");
+            std::process::exit(1);
+        });
+    // Clients are told of the shutdown before the server stops.
+    let shutdown = server.shutdown_on(shutdown_signal());
+    let app = server.into_router();
+
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        if let Err(error) = coordinator::tls::serve(listener, app, tls, shutdown).await {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
         .await
         .unwrap();
 }
 
 #[cfg(feature = "shuttle")]
 #[shuttle_runtime::main]
-async fn main() -> shuttle_axum::ShuttleAxum {
+async fn main(
+    #[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore,
+) -> shuttle_axum::ShuttleAxum {
+    let config = Config::from_secrets(&secrets)
+        .map_err(|error| shuttle_runtime::Error::Custom(error.into()))?;
+    init_tracing(&config);
+    let server = MeetingServer::connect(config).await.map_err(|error| {
+        shuttle_runtime::Error::Custom(shuttle_runtime::CustomError::msg(error))
+    })?;
+    Ok(server.into_router().into())
+}
+
+fn init_tracing(config: &Config) {
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "coordinator=trace".into()),
-        )
+        .with(tracing_subscriber::EnvFilter::new(&config.log))
         .with(tracing_subscriber::fmt::layer())
         .init();
-    Ok(route().into())
 }
 
 #[cfg(feature = "std")]
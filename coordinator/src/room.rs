@@ -0,0 +1,441 @@
+//! Model of a meeting room, as a state machine over the slots of its members.
+//!
+//! Rooms are stored as JSON under their passphrase, and every transition goes
+//! through [`update`], which applies it again whenever another participant,
+//! possibly on another coordinator, changed the room in the meantime.
+//!
+//! Members refresh the time they were last seen while they are connected, so
+//! that the members of a coordinator that crashed are eventually dropped from
+//! their room by the others. Times are in seconds since the Unix epoch, as
+//! coordinators do not share their monotonic clocks.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use protocol::{Envelope, Event, ParticipantId};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::db::{Storage, StorageError};
+
+/// State of a room, following the participants who join and leave it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum RoomState {
+    /// Nobody joined the room yet.
+    Empty,
+    /// A single participant waits for the others.
+    Waiting,
+    /// Several participants are meeting.
+    Paired,
+    /// The last participant left, and the room is being removed. Joining it
+    /// opens it again.
+    Closing,
+}
+
+/// Transition rejected by a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RoomError {
+    /// Every slot of the room is taken.
+    Full,
+    /// The participant is not in the room, such as after it expired.
+    NotMember(ParticipantId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Room {
+    state: RoomState,
+    /// Members of the room, with a slot for every allowed participant.
+    slots: Vec<Option<Member>>,
+    /// Id of the next participant, so that the ids of participants who left
+    /// are never given again while the room exists.
+    next_id: ParticipantId,
+}
+
+/// Participant taking a slot of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Member {
+    id: ParticipantId,
+    /// When the member was last seen, in seconds since the Unix epoch.
+    seen: u64,
+    /// Whether the client of the member supports meetings with more than two
+    /// participants, as negotiated with `Capability::MultiParty`.
+    multi_party: bool,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::Full => write!(f, "the room is full"),
+            RoomError::NotMember(id) => write!(f, "participant {id} is not in the room"),
+        }
+    }
+}
+
+impl Room {
+    pub(crate) fn new(max_participants: u32) -> Room {
+        Room {
+            state: RoomState::Empty,
+            slots: vec![None; max_participants as usize],
+            next_id: 1,
+        }
+    }
+
+    pub(crate) fn state(&self) -> RoomState {
+        self.state
+    }
+
+    /// Takes the first free slot for a new participant, seen at `now`. A room
+    /// with a participant not supporting `multi_party` holds two of them at
+    /// most.
+    ///
+    /// Returns the id of the participant.
+    pub(crate) fn join(&mut self, now: u64, multi_party: bool) -> Result<ParticipantId, RoomError> {
+        let members = self.slots.iter().flatten();
+        let two_party = !multi_party || members.clone().any(|member| !member.multi_party);
+        if two_party && members.count() >= 2 {
+            return Err(RoomError::Full);
+        }
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RoomError::Full)?;
+        let id = self.next_id;
+        *slot = Some(Member {
+            id,
+            seen: now,
+            multi_party,
+        });
+        self.next_id += 1;
+        self.state = match self.state {
+            RoomState::Empty | RoomState::Closing => RoomState::Waiting,
+            RoomState::Waiting | RoomState::Paired => RoomState::Paired,
+        };
+        Ok(id)
+    }
+
+    /// Frees the slot of a participant, for anyone to join again.
+    pub(crate) fn leave(&mut self, id: ParticipantId) -> Result<(), RoomError> {
+        *self.slot(id)? = None;
+        self.state = match self.slots.iter().flatten().count() {
+            0 => RoomState::Closing,
+            1 => RoomState::Waiting,
+            _ => RoomState::Paired,
+        };
+        Ok(())
+    }
+
+    /// Records that a participant is still there at `now`.
+    pub(crate) fn refresh(&mut self, id: ParticipantId, now: u64) -> Result<(), RoomError> {
+        let member = self.slot(id)?.as_mut().unwrap();
+        member.seen = member.seen.max(now);
+        Ok(())
+    }
+
+    /// Frees the slots of the participants not seen for longer than `timeout`
+    /// at `now`.
+    ///
+    /// Returns the ids of these participants.
+    fn drop_stale(&mut self, now: u64, timeout: Duration) -> Vec<ParticipantId> {
+        let stale = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|member| now.saturating_sub(member.seen) > timeout.as_secs())
+            .map(|member| member.id)
+            .collect::<Vec<_>>();
+        for id in &stale {
+            self.leave(*id).unwrap();
+        }
+        stale
+    }
+
+    fn slot(&mut self, id: ParticipantId) -> Result<&mut Option<Member>, RoomError> {
+        self.slots
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|member| member.id == id))
+            .ok_or(RoomError::NotMember(id))
+    }
+}
+
+/// Applies a transition to the room of a passphrase, which is `None` while
+/// there is no room, and stores the room it results in, or removes it for
+/// `None`. Nothing is stored when the transition leaves the room unchanged.
+///
+/// Members not seen for longer than `member_timeout` are dropped from the room
+/// beforehand, and announced to the others as left.
+///
+/// Returns the result of the transition.
+pub(crate) async fn update<T>(
+    db: &dyn Storage,
+    passphrase: &str,
+    member_timeout: Duration,
+    mut transition: impl FnMut(&mut Option<Room>) -> T,
+) -> Result<T, StorageError> {
+    loop {
+        let (before, revision) = match db.get(passphrase).await? {
+            Some((value, revision)) => {
+                (Some(serde_json::from_str::<Room>(&value)?), Some(revision))
+            }
+            None => (None, None),
+        };
+        let mut room = before.clone();
+        let stale = match &mut room {
+            Some(room) => room.drop_stale(now(), member_timeout),
+            None => Vec::new(),
+        };
+        let result = transition(&mut room);
+        if room == before {
+            return Ok(result);
+        }
+        let value = room.map(|room| serde_json::to_string(&room).unwrap());
+        if db.compare_and_set(passphrase, value, revision).await? {
+            for id in stale {
+                debug!("Participant {id} of {passphrase} was not seen for too long.");
+                announce_left(db, passphrase, id).await?;
+            }
+            return Ok(result);
+        }
+    }
+}
+
+/// Returns the state of the room of a passphrase, if there is one.
+pub(crate) async fn state(
+    db: &dyn Storage,
+    passphrase: &str,
+    member_timeout: Duration,
+) -> Result<Option<RoomState>, StorageError> {
+    update(db, passphrase, member_timeout, |room| {
+        room.as_ref().map(Room::state)
+    })
+    .await
+}
+
+/// Announces to the other participants of a room that one of them left.
+pub(crate) async fn announce_left(
+    db: &dyn Storage,
+    passphrase: &str,
+    id: ParticipantId,
+) -> Result<(), StorageError> {
+    let announcement = serde_json::to_string(&Envelope {
+        from: Some(id),
+        to: None,
+        event: Event::PeerLeft(id),
+    })
+    .unwrap();
+    db.publish(&channel_name(passphrase, "room"), announcement)
+        .await?;
+    Ok(())
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Returns the name of a channel of a meeting, such as `room` for the
+/// announcements to every participant, or the id of a participant for its
+/// inbox.
+pub(crate) fn channel_name(passphrase: &str, name: &str) -> String {
+    [passphrase, name].join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::db::Db;
+
+    /// Long enough for no member to be dropped.
+    const TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn participants_wait_then_meet() {
+        let mut room = Room::new(3);
+        assert_eq!(room.state(), RoomState::Empty);
+        assert_eq!(room.join(0, true), Ok(1));
+        assert_eq!(room.state(), RoomState::Waiting);
+        assert_eq!(room.join(0, true), Ok(2));
+        assert_eq!(room.state(), RoomState::Paired);
+        assert_eq!(room.join(0, true), Ok(3));
+        assert_eq!(room.state(), RoomState::Paired);
+    }
+
+    #[test]
+    fn full_room_is_left_unchanged() {
+        let mut room = Room::new(2);
+        room.join(0, true).unwrap();
+        room.join(0, true).unwrap();
+        let full = room.clone();
+        assert_eq!(room.join(0, true), Err(RoomError::Full));
+        assert_eq!(room, full);
+    }
+
+    #[test]
+    fn last_participant_closes_the_room() {
+        let mut room = Room::new(3);
+        let ids = [
+            room.join(0, true).unwrap(),
+            room.join(0, true).unwrap(),
+            room.join(0, true).unwrap(),
+        ];
+        room.leave(ids[1]).unwrap();
+        assert_eq!(room.state(), RoomState::Paired);
+        room.leave(ids[0]).unwrap();
+        assert_eq!(room.state(), RoomState::Waiting);
+        room.leave(ids[2]).unwrap();
+        assert_eq!(room.state(), RoomState::Closing);
+    }
+
+    #[test]
+    fn freed_slot_is_taken_with_a_new_id() {
+        let mut room = Room::new(2);
+        let first = room.join(0, true).unwrap();
+        let second = room.join(0, true).unwrap();
+        room.leave(second).unwrap();
+        assert_eq!(room.state(), RoomState::Waiting);
+
+        assert_eq!(room.join(0, true), Ok(3));
+        assert_eq!(room.state(), RoomState::Paired);
+        room.leave(first).unwrap();
+        assert_eq!(room.join(0, true), Ok(4));
+        assert_eq!(room.join(0, true), Err(RoomError::Full));
+    }
+
+    #[test]
+    fn only_members_leave() {
+        let mut room = Room::new(2);
+        let id = room.join(0, true).unwrap();
+        assert_eq!(room.leave(id + 1), Err(RoomError::NotMember(id + 1)));
+        room.leave(id).unwrap();
+        assert_eq!(room.leave(id), Err(RoomError::NotMember(id)));
+        assert_eq!(room.state(), RoomState::Closing);
+    }
+
+    #[test]
+    fn closing_room_opens_again() {
+        let mut room = Room::new(2);
+        let id = room.join(0, true).unwrap();
+        room.leave(id).unwrap();
+        assert_eq!(room.join(0, true), Ok(id + 1));
+        assert_eq!(room.state(), RoomState::Waiting);
+    }
+
+    #[test]
+    fn two_party_participants_keep_the_room_to_two() {
+        let mut room = Room::new(4);
+        room.join(0, false).unwrap();
+        room.join(0, true).unwrap();
+        assert_eq!(room.join(0, true), Err(RoomError::Full));
+
+        let mut room = Room::new(4);
+        room.join(0, true).unwrap();
+        room.join(0, true).unwrap();
+        assert_eq!(room.join(0, false), Err(RoomError::Full));
+        assert_eq!(room.join(0, true), Ok(3));
+    }
+
+    #[tokio::test]
+    async fn concurrent_joins_take_distinct_slots() {
+        let db = Arc::new(Db::new());
+        let joins = (0..8).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                update(db.as_ref(), "room", TIMEOUT, |room| {
+                    room.get_or_insert_with(|| Room::new(6)).join(now(), true)
+                })
+                .await
+                .unwrap()
+            })
+        });
+        let results = futures_util::future::join_all(joins)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let mut ids = results.iter().flatten().copied().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (1..=6).collect::<Vec<_>>());
+        let full = results
+            .iter()
+            .filter(|result| **result == Err(RoomError::Full));
+        assert_eq!(full.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn removed_room_is_created_again() {
+        let db = Db::new();
+        update(&db, "room", TIMEOUT, |room| {
+            room.get_or_insert_with(|| Room::new(2)).join(now(), true)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        update(&db, "room", TIMEOUT, |room| *room = None)
+            .await
+            .unwrap();
+        assert_eq!(db.get("room").await.unwrap(), None);
+
+        let room = update(&db, "room", TIMEOUT, |room| {
+            room.get_or_insert_with(|| Room::new(2))
+                .join(now(), true)
+                .unwrap();
+            room.clone()
+        })
+        .await
+        .unwrap();
+        assert_eq!(room.map(|room| room.state()), Some(RoomState::Waiting));
+    }
+
+    #[test]
+    fn members_not_seen_for_too_long_are_dropped() {
+        let timeout = Duration::from_secs(60);
+        let mut room = Room::new(3);
+        let first = room.join(0, true).unwrap();
+        let second = room.join(0, true).unwrap();
+        let third = room.join(50, true).unwrap();
+        room.refresh(first, 100).unwrap();
+        assert_eq!(room.refresh(4, 100), Err(RoomError::NotMember(4)));
+
+        assert!(room.drop_stale(60, timeout).is_empty());
+        assert_eq!(room.drop_stale(100, timeout), [second]);
+        assert_eq!(room.state(), RoomState::Paired);
+        assert_eq!(room.drop_stale(200, timeout), [first, third]);
+        assert_eq!(room.state(), RoomState::Closing);
+    }
+
+    #[tokio::test]
+    async fn stale_members_are_announced_as_left() {
+        let db = Db::new();
+        let mut announcements = db.subscribe("room:room").await.unwrap();
+        let stale = update(&db, "room", TIMEOUT, |room| {
+            room.get_or_insert_with(|| Room::new(2))
+                .join(now() - 120, true)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let timeout = Duration::from_secs(60);
+        let id = update(&db, "room", timeout, |room| {
+            room.get_or_insert_with(|| Room::new(2)).join(now(), true)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(id, stale + 1);
+        let state = state(&db, "room", timeout).await.unwrap();
+        assert_eq!(state, Some(RoomState::Waiting));
+
+        let announcement = announcements.next().await.unwrap();
+        let envelope = serde_json::from_str::<Envelope>(&announcement).unwrap();
+        assert!(matches!(envelope.event, Event::PeerLeft(id) if id == stale));
+    }
+}
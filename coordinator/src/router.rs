@@ -1,34 +1,139 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc, time::Duration};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        OriginalUri, State,
     },
-    response::IntoResponse,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
 use futures_util::{SinkExt, StreamExt};
-use protocol::{Event, Role};
-use tokio::sync::mpsc;
+#[cfg(feature = "embed")]
+use memory_serve::{load_assets, CacheControl, MemoryServe};
+use protocol::{
+    Capability, Codec, Envelope, ErrorCode, Event, Frame, Hello, IceServer, Joined, ParticipantId,
+    Waiting, Welcome, PROTOCOL_VERSION,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
 use tower_http::services::ServeDir;
 use tracing::{debug, warn};
 
-use crate::db::DbHolder;
+use crate::{
+    config::Config,
+    db::{DbHolder, Storage, StorageError},
+    room::{self, channel_name, Room, RoomError, RoomState},
+    server::Hooks,
+    session::{Seat, Sessions},
+};
+
+/// Optional protocol features supported by the coordinator.
+const CAPABILITIES: &[Capability] = &[Capability::MultiParty, Capability::Cbor];
+
+/// How long a meeting is kept in the storage after its participants stop
+/// refreshing it, such as when a coordinator crashes, on top of the grace
+/// period of their sessions.
+pub(crate) const ROOM_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How often every participant refreshes the time to live of its meeting,
+/// and the time it was last seen in its room.
+const ROOM_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a participant is kept in its room without being seen, such as
+/// when its coordinator crashes, on top of the grace period of its session.
+const MEMBER_TIMEOUT: Duration = ROOM_REFRESH_INTERVAL.saturating_mul(3);
 
 struct AppState {
     pub db_holder: DbHolder,
+    pub sessions: Sessions,
+    pub config: Config,
+    pub hooks: Hooks,
+    pub shutdown: watch::Sender<bool>,
 }
 
-pub fn route() -> Router {
-    let db_holder = DbHolder::new();
-    let app_state = AppState { db_holder };
+pub(crate) fn route(
+    config: Config,
+    db: Arc<dyn Storage>,
+    hooks: Hooks,
+    shutdown: watch::Sender<bool>,
+) -> Router {
+    let db_holder = DbHolder::new(db);
+    let sessions = Sessions::new();
+    let static_files = static_files(config.static_dir.as_deref());
+    let base_path = format!("{}/", config.base_path.trim_end_matches('/'));
+    let app_state = AppState {
+        db_holder,
+        sessions,
+        config,
+        hooks,
+        shutdown,
+    };
+
+    let state = Arc::new(app_state);
 
-    Router::new()
-        .nest_service("/", ServeDir::new("static"))
+    let app = static_files
+        .route("/", get(index_handler))
         .route("/websocket", get(websocket_handler))
-        .with_state(app_state.into())
+        .route("/protocol/schema.json", get(schema_handler))
+        .with_state(state.clone());
+    match base_path.trim_end_matches('/') {
+        "" => app,
+        // The nested index only matches the base path without its trailing
+        // slash.
+        nest_path => Router::new()
+            .nest(nest_path, app)
+            .route(&base_path, get(index_handler).with_state(state)),
+    }
+}
+
+/// Serves the peer web page from a directory, or else from the assets
+/// embedded at build time.
+fn static_files<S>(static_dir: Option<&Path>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match static_dir {
+        Some(static_dir) => Router::new().fallback_service(ServeDir::new(static_dir)),
+        // Assets are not fingerprinted, so they are always revalidated with
+        // their ETag to pick up a new build.
+        #[cfg(feature = "embed")]
+        None => MemoryServe::new(load_assets!("../static"))
+            .index_file(None)
+            .html_cache_control(CacheControl::NoCache)
+            .cache_control(CacheControl::NoCache)
+            .into_router(),
+        #[cfg(not(feature = "embed"))]
+        None => Router::new(),
+    }
+}
+
+/// Serves the page of the peer, with the path it is requested at as the base
+/// URL of the page, which the peer derives the WebSocket URL from. This keeps
+/// working when the router is nested into another one.
+async fn index_handler(State(state): State<Arc<AppState>>, uri: OriginalUri) -> Response {
+    let index = match &state.config.static_dir {
+        Some(static_dir) => match tokio::fs::read_to_string(static_dir.join("index.html")).await {
+            Ok(index) => index,
+            Err(error) => {
+                warn!("Failed to read the page of the peer: {error}");
+                return StatusCode::NOT_FOUND.into_response();
+            }
+        },
+        #[cfg(feature = "embed")]
+        None => include_str!("../../static/index.html").to_owned(),
+        #[cfg(not(feature = "embed"))]
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let base = format!(
+        "<head>\n  <base href=\"{}/\" />",
+        uri.path().trim_end_matches('/')
+    );
+    Html(index.replacen("<head>", &base, 1)).into_response()
 }
 
 async fn websocket_handler(
@@ -38,6 +143,11 @@ async fn websocket_handler(
     ws.on_upgrade(|socket| websocket(socket, state))
 }
 
+/// Publishes the JSON Schema of the signaling protocol for non-Rust clients.
+async fn schema_handler() -> impl IntoResponse {
+    Json(protocol::schema())
+}
+
 async fn websocket(stream: WebSocket, state: Arc<AppState>) {
     let (mut sender, mut receiver) = stream.split();
 
@@ -51,134 +161,557 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
         }
     });
 
-    // Get passphrase.
-    let passphrase = match receiver.next().await {
-        Some(Ok(Message::Text(msg))) => match serde_json::from_str::<Event>(&msg) {
-            Ok(Event::Passphrase(passphrase)) => passphrase,
-            _ => {
-                tx.send(Message::Text(
-                    serde_json::to_string(&Event::Error("Invalid passphrase.".into())).unwrap(),
-                ))
+    // Handshake.
+    let welcome = match receiver.next().await {
+        Some(Ok(Message::Text(msg))) => match Frame::Text(msg).decode() {
+            Ok(Envelope {
+                event: Event::Hello(hello),
+                ..
+            }) => handshake(hello, state.config.ice.ice_servers()),
+            _ => Err(ErrorCode::MissingHello),
+        },
+        _ => Err(ErrorCode::MissingHello),
+    };
+    // The handshake always happens in JSON, and the negotiated codec is used
+    // from then on.
+    let (codec, multi_party) = match welcome {
+        Ok(welcome) => {
+            let codec = welcome.codec();
+            let multi_party = welcome.capabilities.contains(&Capability::MultiParty);
+            if tx
+                .send(message(Codec::Json, &Event::Welcome(welcome).into()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            (codec, multi_party)
+        }
+        Err(error) => {
+            warn!("Rejected client: {error}");
+            let _ = tx
+                .send(message(Codec::Json, &Event::Error(error).into()))
+                .await;
+            return;
+        }
+    };
+    debug!("The client's codec is: {codec:?}.");
+
+    // Join a meeting with a passphrase, or resume a session.
+    let db = state.db_holder.db();
+    let ttl = ROOM_TTL + state.config.resume_grace_period;
+    let member_timeout = MEMBER_TIMEOUT + state.config.resume_grace_period;
+    let (token, seat, room) = match receiver.next().await.and_then(|msg| frame(msg.ok()?)) {
+        Some(frame) => match frame.decode() {
+            Ok(Envelope {
+                event: Event::Passphrase(passphrase),
+                ..
+            }) => {
+                debug!("Passphrase: {passphrase}");
+                // Listen to the room before taking a seat, so that nobody
+                // joining right after this participant is missed.
+                let room = match db.subscribe(&channel_name(&passphrase, "room")).await {
+                    Ok(room) => room,
+                    Err(error) => {
+                        warn!("Failed to subscribe to the meeting {passphrase}: {error}");
+                        let _ = tx
+                            .send(message(codec, &Event::Error(ErrorCode::Unavailable).into()))
+                            .await;
+                        return;
+                    }
+                };
+                match join(
+                    db.as_ref(),
+                    &state.sessions,
+                    state.config.max_participants,
+                    passphrase,
+                    ttl,
+                    member_timeout,
+                    multi_party,
+                )
                 .await
-                .unwrap();
+                {
+                    Ok((token, seat)) => {
+                        state.hooks.joined(&seat.passphrase, seat.id);
+                        (token, seat, Some(room))
+                    }
+                    Err(error) => {
+                        let _ = tx.send(message(codec, &Event::Error(error).into())).await;
+                        return;
+                    }
+                }
+            }
+            Ok(Envelope {
+                event: Event::Resume(token),
+                ..
+            }) => match state.sessions.resume(&token) {
+                Some(seat) => (token, seat, None),
+                None => {
+                    let _ = tx
+                        .send(message(
+                            codec,
+                            &Event::Error(ErrorCode::ResumeFailed).into(),
+                        ))
+                        .await;
+                    warn!("Received an expired resume token from client.");
+                    return;
+                }
+            },
+            _ => {
+                let _ = tx
+                    .send(message(
+                        codec,
+                        &Event::Error(ErrorCode::InvalidPassphrase).into(),
+                    ))
+                    .await;
                 warn!("Received invalid passphrase from client.");
                 return;
             }
         },
         _ => {
-            tx.send(Message::Text(
-                serde_json::to_string(&Event::Error(
-                    "First message should be a passphrase string.".into(),
+            let _ = tx
+                .send(message(
+                    codec,
+                    &Event::Error(ErrorCode::MissingPassphrase).into(),
                 ))
-                .unwrap(),
-            ))
-            .await
-            .unwrap();
+                .await;
             warn!("No passphrase string received.");
             return;
         }
     };
-    debug!("Passphrase: {passphrase}");
+    let Seat {
+        passphrase,
+        id,
+        generation,
+        ..
+    } = seat;
+    debug!("The client's participant id is: {id}.");
 
-    let db = state.db_holder.db();
+    let inbox_channel_name = channel_name(&passphrase, &id.to_string());
+    let room_channel_name = channel_name(&passphrase, "room");
 
-    // Determine a role, initiator or responder.
-    let role = match db.set(passphrase.clone()) {
-        1 => Role::Initiator,
-        2 => Role::Responder,
-        _ => {
-            tx.send(Message::Text(
-                serde_json::to_string(&Event::Error(
-                    "A session with the same passphrase already exists!".into(),
-                ))
-                .unwrap(),
-            ))
-            .await
-            .unwrap();
-            warn!(
-                "A client with passphrase {} is rejected to join this session",
-                passphrase
-            );
+    // Forward messages addressed to this participant, and announcements of
+    // other participants in the room. Only a resumed session still has to
+    // subscribe to the room.
+    let tx_clone = tx.clone();
+    let resumed = room.is_none();
+    let subscriptions: Result<_, StorageError> = async {
+        let inbox = db.subscribe(&inbox_channel_name).await?;
+        let room = match room {
+            Some(room) => room,
+            None => db.subscribe(&room_channel_name).await?,
+        };
+        Ok((inbox, room))
+    }
+    .await;
+    let (mut inbox, mut room) = match subscriptions {
+        Ok(subscriptions) => subscriptions,
+        Err(error) => {
+            warn!("Failed to subscribe participant {id}: {error}");
+            let _ = tx
+                .send(message(codec, &Event::Error(ErrorCode::Unavailable).into()))
+                .await;
+            end_session(state, token, generation, passphrase, id, false).await;
             return;
         }
     };
-    debug!("The client's role is: {role}.");
 
-    let channel_for_role = channel_name(passphrase.clone(), &role);
-    let channel_for_opposite_role = channel_name(passphrase.clone(), &role.opposite());
+    // Signal coordination. The seat is given up at once when the connection
+    // is already gone.
+    let joined = Event::Joined(Joined {
+        id,
+        resume_token: token.clone(),
+    });
+    if tx.send(message(codec, &joined.into())).await.is_err() {
+        end_session(state, token, generation, passphrase, id, true).await;
+        return;
+    }
+    // A participant alone in the meeting waits for others until the timeout,
+    // which starts over when its session is resumed.
+    let wait_timeout = state.config.wait_timeout;
+    let mut deadline = match room::state(db.as_ref(), &passphrase, member_timeout).await {
+        Ok(room_state) => {
+            (room_state == Some(RoomState::Waiting)).then(|| Instant::now() + wait_timeout)
+        }
+        Err(error) => {
+            warn!("Failed to read the meeting {passphrase}: {error}");
+            None
+        }
+    };
+    if deadline.is_some() && tx.send(waiting(codec, wait_timeout)).await.is_err() {
+        end_session(state, token, generation, passphrase, id, true).await;
+        return;
+    }
 
-    // Exchange messages between initiator and responder.
-    let tx_clone = tx.clone();
-    let mut subscriber = db.subscribe(channel_for_opposite_role.clone());
-    let subscribe_task = tokio::spawn(async move {
-        while let Ok(msg) = subscriber.recv().await {
-            tx_clone.send(Message::Text(msg)).await.unwrap();
+    // Relays the subscriptions to the client. Returns whether nobody joined in
+    // time.
+    let room_db = db.clone();
+    let room_passphrase = passphrase.clone();
+    let mut subscribe_task = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = inbox.next() => msg,
+                msg = room.next() => msg,
+                () = wait_until(deadline) => {
+                    debug!("Nobody joined participant {id} in time.");
+                    let timed_out = Event::Error(ErrorCode::PeerTimedOut).into();
+                    let _ = tx_clone.send(message(codec, &timed_out)).await;
+                    return true;
+                }
+            };
+            // The subscriptions end along with the connection to the storage.
+            let Some(msg) = msg else {
+                return false;
+            };
+            let envelope = serde_json::from_str::<Envelope>(&msg);
+            // Skip the announcement of this participant itself.
+            if matches!(&envelope, Ok(Envelope { from, .. }) if *from == Some(id)) {
+                continue;
+            }
+            let msg = match relay(codec, msg) {
+                Ok(msg) => msg,
+                Err(error) => {
+                    warn!("Skipped invalid event for participant {id}: {error}");
+                    continue;
+                }
+            };
+            if tx_clone.send(msg).await.is_err() {
+                return false;
+            }
+            match envelope.map(|envelope| envelope.event) {
+                Ok(Event::PeerJoined(_)) => deadline = None,
+                // Wait for others again once alone.
+                Ok(Event::PeerLeft(_)) if deadline.is_none() => {
+                    let room_state =
+                        room::state(room_db.as_ref(), &room_passphrase, member_timeout).await;
+                    if let Ok(Some(RoomState::Waiting)) = room_state {
+                        deadline = Some(Instant::now() + wait_timeout);
+                        if tx_clone.send(waiting(codec, wait_timeout)).await.is_err() {
+                            return false;
+                        }
+                    }
+                }
+                _ => {}
+            }
         }
     });
 
     let db_clone = db.clone();
-    let channel_for_role_clone = channel_for_role.clone();
+    let passphrase_clone = passphrase.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(Message::Text(msg))) = receiver.next().await {
-            if matches!(
-                serde_json::from_str::<Event>(&msg).unwrap(),
-                Event::CloseConnection
-            ) {
-                // Return from the receiving task will end this session.
-                return;
+        while let Some(Ok(msg)) = receiver.next().await {
+            // Browsers close the connection when the page is left, which
+            // never resumes the session, unlike a dropped connection.
+            if let Message::Close(_) = msg {
+                return true;
             }
-            if db_clone.publish(&channel_for_role_clone, msg) == 0 {
-                warn!("Publish not successful.");
+            let Some(frame) = frame(msg) else {
+                continue;
+            };
+            let mut envelope = match frame.decode() {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    warn!("Received invalid event from participant {id}: {error}");
+                    continue;
+                }
+            };
+            // Never trust the sender claimed by a client.
+            envelope.from = Some(id);
+
+            match (&envelope.event, envelope.to) {
+                // Relay signaling events to their recipient.
+                (Event::Offer(_) | Event::Answer(_) | Event::IceCandidate(_), Some(to)) => {
+                    let channel = channel_name(&passphrase_clone, &to.to_string());
+                    match db_clone
+                        .publish(&channel, serde_json::to_string(&envelope).unwrap())
+                        .await
+                    {
+                        Ok(0) => warn!("Publish not successful."),
+                        Ok(_) => {}
+                        Err(error) => warn!("Failed to publish to {channel}: {error}"),
+                    }
+                }
+                // Return from the receiving task will end this session.
+                (Event::CloseConnection, None) => return true,
+                (event, to) => {
+                    warn!("Unexpected event from participant {id} to {to:?}: {event:?}");
+                }
             }
         }
+        false
     });
 
-    // Signal coordination.
-    let notification_channel_name = [&passphrase, "notification"].join(":");
-    match role {
-        Role::Initiator => {
-            db.subscribe(notification_channel_name.clone())
-                .recv()
-                .await
-                .unwrap();
+    // A resumed session is still known to the other participants.
+    if !resumed {
+        let announcement = serde_json::to_string(&Envelope {
+            from: Some(id),
+            to: None,
+            event: Event::PeerJoined(id),
+        })
+        .unwrap();
+        if let Err(error) = db.publish(&room_channel_name, announcement).await {
+            warn!("Failed to announce participant {id}: {error}");
         }
-        Role::Responder => {
-            if db.publish(&notification_channel_name, String::from("")) == 0 {
-                warn!("Publish not successful.");
+    }
+
+    // If any one of the tasks run to completion, we abort the other. The
+    // shutdown waits for this session to end as long as it is watched.
+    let mut refresh = tokio::time::interval(ROOM_REFRESH_INTERVAL);
+    let mut shutdown = state.shutdown.subscribe();
+    let closed = loop {
+        tokio::select! {
+            _ =(&mut send_task) => {
+                recv_task.abort();
+                subscribe_task.abort();
+                break false;
+            },
+            closed = (&mut recv_task) => {
+                send_task.abort();
+                subscribe_task.abort();
+                break closed.unwrap_or_default();
+            },
+            // The client may resume its session with new subscriptions,
+            // unless nobody joined it in time.
+            timed_out = (&mut subscribe_task) => {
+                recv_task.abort();
+                if timed_out.unwrap_or_default() {
+                    // Let the error reach the client before closing.
+                    drop(tx);
+                    let _ = (&mut send_task).await;
+                    break true;
+                }
+                send_task.abort();
+                break false;
+            },
+            // Leave the meeting for good, as no other coordinator may resume
+            // the session.
+            () = shutting_down(&mut shutdown) => {
+                recv_task.abort();
+                subscribe_task.abort();
+                let shutting_down = Event::Error(ErrorCode::ShuttingDown).into();
+                let _ = tx.send(message(codec, &shutting_down)).await;
+                // Let the error reach the client before closing.
+                drop(tx);
+                let _ = (&mut send_task).await;
+                break true;
+            }
+            // Keep the meeting from expiring while the participant is
+            // connected.
+            _ = refresh.tick() => {
+                refresh_room(db.as_ref(), &passphrase, id, ttl, member_timeout).await;
             }
         }
+    };
+
+    end_session(state, token, generation, passphrase, id, closed).await;
+}
+
+/// Cleaning task. The seat is kept for a while when the connection drops,
+/// unless the session was resumed by another connection in the meantime.
+async fn end_session(
+    state: Arc<AppState>,
+    token: String,
+    generation: u64,
+    passphrase: String,
+    id: ParticipantId,
+    closed: bool,
+) {
+    let db = state.db_holder.db();
+    let member_timeout = MEMBER_TIMEOUT + state.config.resume_grace_period;
+    if closed {
+        if state.sessions.close(&token, generation) {
+            leave(db.as_ref(), &passphrase, id, member_timeout).await;
+            state.hooks.left(&passphrase, id);
+        }
+    } else if state.sessions.detach(&token, generation) {
+        debug!("Session {passphrase}:{id} detached.");
+        tokio::spawn(async move {
+            tokio::time::sleep(state.config.resume_grace_period).await;
+            if state.sessions.expire(&token, generation) {
+                leave(db.as_ref(), &passphrase, id, member_timeout).await;
+                state.hooks.left(&passphrase, id);
+            }
+        });
     }
-    let role_clone = role.clone();
-    tx.send(Message::Text(
-        serde_json::to_string(&Event::Role(role_clone)).unwrap(),
-    ))
-    .await
-    .unwrap();
+}
 
-    // If any one of the tasks run to completion, we abort the other.
-    tokio::select! {
-        _ =(&mut send_task) => {
-            recv_task.abort();
-            subscribe_task.abort();
-        },
-        _ = (&mut recv_task) => {
-            send_task.abort();
-            subscribe_task.abort();
-        },
+/// Takes a seat in a meeting, if there is one left. Meetings with a client
+/// not supporting `multi_party` are limited to two participants.
+///
+/// Returns the resume token and the seat of the new participant.
+async fn join(
+    db: &dyn Storage,
+    sessions: &Sessions,
+    max_participants: u32,
+    passphrase: String,
+    ttl: Duration,
+    member_timeout: Duration,
+    multi_party: bool,
+) -> Result<(String, Seat), ErrorCode> {
+    let joined: Result<_, StorageError> = async {
+        let now = room::now();
+        let joined = room::update(db, &passphrase, member_timeout, |room| {
+            room.get_or_insert_with(|| Room::new(max_participants))
+                .join(now, multi_party)
+        })
+        .await?;
+        db.expire(&passphrase, ttl).await?;
+        Ok(joined)
+    }
+    .await;
+    match joined {
+        Ok(Ok(id)) => Ok(sessions.register(passphrase, id)),
+        Ok(Err(error)) => {
+            warn!(
+                "A client with passphrase {} is rejected to join this session: {error}",
+                passphrase
+            );
+            Err(ErrorCode::RoomFull)
+        }
+        Err(error) => {
+            warn!("Failed to join the meeting {passphrase}: {error}");
+            Err(ErrorCode::Unavailable)
+        }
+    }
+}
+
+/// Refreshes the time to live of a meeting, along with the channels of a
+/// participant, and the time the participant was last seen in its room.
+async fn refresh_room(
+    db: &dyn Storage,
+    passphrase: &str,
+    id: ParticipantId,
+    ttl: Duration,
+    member_timeout: Duration,
+) {
+    let result: Result<(), StorageError> = async {
+        let now = room::now();
+        let refreshed = room::update(db, passphrase, member_timeout, |room| {
+            room.as_mut()
+                .ok_or(RoomError::NotMember(id))?
+                .refresh(id, now)
+        })
+        .await?;
+        if let Err(error) = refreshed {
+            warn!("Failed to refresh participant {id} in {passphrase}: {error}");
+        }
+        db.expire(passphrase, ttl).await?;
+        db.expire_channel(&channel_name(passphrase, "room"), ttl)
+            .await?;
+        db.expire_channel(&channel_name(passphrase, &id.to_string()), ttl)
+            .await?;
+        Ok(())
+    }
+    .await;
+    if let Err(error) = result {
+        warn!("Failed to refresh the meeting {passphrase}: {error}");
+    }
+}
+
+/// Gives up the seat of a participant, and announces it to the others. The
+/// room is removed along with its last participant.
+async fn leave(db: &dyn Storage, passphrase: &str, id: ParticipantId, member_timeout: Duration) {
+    let result: Result<(), StorageError> = async {
+        db.delete_channel(&channel_name(passphrase, &id.to_string()))
+            .await?;
+        // The room is removed as soon as it is closing. Its channel is left to
+        // expire instead, as a newcomer may already be subscribed to it.
+        let left = room::update(
+            db,
+            passphrase,
+            member_timeout,
+            |room| -> Result<_, RoomError> {
+                let current = room.as_mut().ok_or(RoomError::NotMember(id))?;
+                current.leave(id)?;
+                let closing = current.state() == RoomState::Closing;
+                if closing {
+                    *room = None;
+                }
+                Ok(closing)
+            },
+        )
+        .await?;
+        match left {
+            Ok(true) => {}
+            Ok(false) => room::announce_left(db, passphrase, id).await?,
+            Err(error) => warn!("Participant {id} already left {passphrase}: {error}"),
+        }
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => debug!("Session {passphrase}:{id} ended."),
+        Err(error) => warn!("Failed to end session {passphrase}:{id}: {error}"),
+    }
+}
+
+/// Checks the compatibility of a client and negotiates the optional features.
+fn handshake(hello: Hello, ice_servers: Vec<IceServer>) -> Result<Welcome, ErrorCode> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(ErrorCode::UnsupportedVersion {
+            supported: PROTOCOL_VERSION,
+        });
+    }
+    debug!(
+        "Hello from a {:?} client with capabilities {:?}",
+        hello.client_kind, hello.capabilities
+    );
+
+    Ok(Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: hello
+            .capabilities
+            .into_iter()
+            .filter(|capability| CAPABILITIES.contains(capability))
+            .collect(),
+        ice_servers,
+    })
+}
+
+/// Waits until a deadline, or forever without one.
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
     }
+}
 
-    // Cleaning task.
-    db.delete(&passphrase);
-    for channel in [
-        channel_for_role,
-        channel_for_opposite_role,
-        notification_channel_name,
-    ] {
-        db.delete_channel(&channel);
+/// Waits until the server is shutting down.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    // The sender lives as long as the sessions.
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+fn message(codec: Codec, envelope: &Envelope) -> Message {
+    match codec.encode(envelope) {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
     }
-    debug!("Session {passphrase}:{role} ended.");
 }
 
-fn channel_name(prefix: String, role: &Role) -> String {
-    [prefix, role.to_string()].join(":")
+/// Tells a participant alone in the meeting how long it waits for others.
+fn waiting(codec: Codec, wait_timeout: Duration) -> Message {
+    let waiting = Waiting {
+        timeout_secs: wait_timeout.as_secs(),
+    };
+    message(codec, &Event::Waiting(waiting).into())
+}
+
+/// Converts a message published on a channel, which is always JSON, to a
+/// message for the client. JSON is relayed as is, even when this coordinator
+/// fails to decode it, such as an event from a newer version.
+fn relay(codec: Codec, msg: String) -> Result<Message, serde_json::Error> {
+    match codec {
+        Codec::Json => Ok(Message::Text(msg)),
+        codec => Ok(message(codec, &serde_json::from_str(&msg)?)),
+    }
+}
+
+/// Returns the frame of a data message, or `None` for control messages.
+fn frame(msg: Message) -> Option<Frame> {
+    match msg {
+        Message::Text(text) => Some(Frame::Text(text)),
+        Message::Binary(bytes) => Some(Frame::Binary(bytes)),
+        _ => None,
+    }
 }
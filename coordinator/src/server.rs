@@ -0,0 +1,160 @@
+//! Builder of the signaling server.
+
+use std::{fmt, future::Future, sync::Arc};
+
+use axum::Router;
+use protocol::ParticipantId;
+use tokio::sync::watch;
+
+#[cfg(feature = "nats")]
+use crate::db::NatsDb;
+#[cfg(feature = "redis")]
+use crate::db::RedisDb;
+#[cfg(feature = "nats")]
+use crate::router::ROOM_TTL;
+use crate::{
+    config::Config,
+    db::{Db, Storage, StorageError},
+    router::route,
+};
+
+/// Callback run with the passphrase of a meeting and the id of a participant.
+type Hook = Arc<dyn Fn(&str, ParticipantId) + Send + Sync>;
+
+/// Builds the router of the signaling server, along with the web page of the
+/// peer.
+///
+/// The router serves every route under `base_path` of the configuration. It
+/// may also be nested into another router, in which case the page of the peer
+/// is served under the nested path, such as `/meet/`, and the nested path
+/// without its trailing slash.
+///
+/// ```no_run
+/// use coordinator::{Config, MeetingServer};
+///
+/// let meetings = MeetingServer::new(Config::default())
+///     .on_join(|passphrase, id| println!("{id} joined {passphrase}"))
+///     .into_router();
+/// let app: axum::Router = axum::Router::new().nest("/meet", meetings);
+/// ```
+pub struct MeetingServer {
+    config: Config,
+    db: Arc<dyn Storage>,
+    hooks: Hooks,
+    /// Whether the server is shutting down, watched by every session.
+    shutdown: watch::Sender<bool>,
+}
+
+/// Callbacks on the lifecycle of participants.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    on_join: Option<Hook>,
+    on_leave: Option<Hook>,
+}
+
+impl MeetingServer {
+    pub fn new(config: Config) -> MeetingServer {
+        MeetingServer {
+            config,
+            db: Arc::new(Db::new()),
+            hooks: Hooks::default(),
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    /// Creates the server with the storage selected by the configuration,
+    /// connecting to it if needed.
+    pub async fn connect(config: Config) -> Result<MeetingServer, StorageError> {
+        #[cfg(feature = "redis")]
+        if let Some(url) = &config.redis_url {
+            let db = RedisDb::connect(url).await?;
+            return Ok(MeetingServer::new(config).storage(db));
+        }
+        #[cfg(feature = "nats")]
+        if let Some(url) = &config.nats_url {
+            let db = NatsDb::connect(url, ROOM_TTL + config.resume_grace_period).await?;
+            return Ok(MeetingServer::new(config).storage(db));
+        }
+        Ok(MeetingServer::new(config))
+    }
+
+    /// Sets the storage of the meetings, such as to share it between several
+    /// routers. Defaults to a new [`Db`] in memory.
+    pub fn storage(mut self, db: impl Storage) -> MeetingServer {
+        self.db = Arc::new(db);
+        self
+    }
+
+    /// Runs a callback whenever a participant joins a meeting. Resumed
+    /// sessions do not join again.
+    pub fn on_join(mut self, hook: impl Fn(&str, ParticipantId) + Send + Sync + 'static) -> Self {
+        self.hooks.on_join = Some(Arc::new(hook));
+        self
+    }
+
+    /// Runs a callback whenever a participant leaves a meeting, either by
+    /// closing the connection or once the grace period of its session expires.
+    pub fn on_leave(mut self, hook: impl Fn(&str, ParticipantId) + Send + Sync + 'static) -> Self {
+        self.hooks.on_leave = Some(Arc::new(hook));
+        self
+    }
+
+    /// Ends every session once `signal` completes, sending
+    /// `ErrorCode::ShuttingDown` to its client first.
+    ///
+    /// Returns a future completing once the sessions have ended, to shut the
+    /// server down gracefully with, since upgraded WebSockets are not waited
+    /// for otherwise.
+    ///
+    /// ```no_run
+    /// # async fn serve(listener: tokio::net::TcpListener) {
+    /// use coordinator::{Config, MeetingServer};
+    ///
+    /// let server = MeetingServer::new(Config::default());
+    /// let shutdown = server.shutdown_on(async {
+    ///     let _ = tokio::signal::ctrl_c().await;
+    /// });
+    /// axum::serve(listener, server.into_router())
+    ///     .with_graceful_shutdown(shutdown)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn shutdown_on(
+        &self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let shutdown = self.shutdown.clone();
+        async move {
+            signal.await;
+            shutdown.send_replace(true);
+            shutdown.closed().await;
+        }
+    }
+
+    pub fn into_router(self) -> Router {
+        route(self.config, self.db, self.hooks, self.shutdown)
+    }
+}
+
+impl fmt::Debug for MeetingServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeetingServer")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Hooks {
+    pub(crate) fn joined(&self, passphrase: &str, id: ParticipantId) {
+        if let Some(hook) = &self.on_join {
+            hook(passphrase, id);
+        }
+    }
+
+    pub(crate) fn left(&self, passphrase: &str, id: ParticipantId) {
+        if let Some(hook) = &self.on_leave {
+            hook(passphrase, id);
+        }
+    }
+}
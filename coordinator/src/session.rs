@@ -0,0 +1,144 @@
+//! Registry of resumable sessions.
+//!
+//! Every participant receives a resume token when joining a meeting. When its
+//! WebSocket drops, the seat of the participant is kept for a grace period,
+//! during which a new WebSocket may resume the session with the token.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use protocol::ParticipantId;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    seats: Mutex<HashMap<String, Seat>>,
+}
+
+/// Seat of a participant in a meeting.
+#[derive(Debug, Clone)]
+pub(crate) struct Seat {
+    pub(crate) passphrase: String,
+    pub(crate) id: ParticipantId,
+    /// Incremented on every resume, so that only the latest WebSocket of a
+    /// session may release its seat.
+    pub(crate) generation: u64,
+    /// Whether a WebSocket is attached to the session.
+    attached: bool,
+}
+
+impl Sessions {
+    pub(crate) fn new() -> Sessions {
+        Sessions {
+            seats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers the seat of a new participant.
+    ///
+    /// Returns the resume token of the session along with the seat.
+    pub(crate) fn register(&self, passphrase: String, id: ParticipantId) -> (String, Seat) {
+        let token = Uuid::new_v4().simple().to_string();
+        let seat = Seat {
+            passphrase,
+            id,
+            generation: 0,
+            attached: true,
+        };
+        self.seats
+            .lock()
+            .unwrap()
+            .insert(token.clone(), seat.clone());
+        (token, seat)
+    }
+
+    /// Attaches a new WebSocket to a session.
+    ///
+    /// Returns the seat of the session, with the generation owned by the new
+    /// WebSocket, or `None` if the session has expired.
+    pub(crate) fn resume(&self, token: &str) -> Option<Seat> {
+        let mut seats = self.seats.lock().unwrap();
+        let seat = seats.get_mut(token)?;
+        seat.generation += 1;
+        seat.attached = true;
+        Some(seat.clone())
+    }
+
+    /// Detaches the WebSocket owning `generation` from a session.
+    ///
+    /// Returns `false` if the session was resumed by another WebSocket since.
+    pub(crate) fn detach(&self, token: &str, generation: u64) -> bool {
+        let mut seats = self.seats.lock().unwrap();
+        match seats.get_mut(token) {
+            Some(seat) if seat.generation == generation => {
+                seat.attached = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes a session detached by the WebSocket owning `generation`.
+    ///
+    /// Returns `false` if the session was resumed since.
+    pub(crate) fn expire(&self, token: &str, generation: u64) -> bool {
+        let mut seats = self.seats.lock().unwrap();
+        match seats.get(token) {
+            Some(seat) if seat.generation == generation && !seat.attached => {
+                seats.remove(token);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes a session closed by the WebSocket owning `generation`.
+    ///
+    /// Returns `false` if the session was resumed by another WebSocket since.
+    pub(crate) fn close(&self, token: &str, generation: u64) -> bool {
+        let mut seats = self.seats.lock().unwrap();
+        match seats.get(token) {
+            Some(seat) if seat.generation == generation => {
+                seats.remove(token);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_generation_cannot_detach() {
+        let sessions = Sessions::new();
+        let (token, seat) = sessions.register("room".into(), 1);
+        let resumed = sessions.resume(&token).unwrap();
+        assert_eq!(resumed.id, 1);
+        assert_eq!(resumed.generation, seat.generation + 1);
+        assert!(!sessions.detach(&token, seat.generation));
+        assert!(sessions.detach(&token, resumed.generation));
+    }
+
+    #[test]
+    fn resumed_session_does_not_expire() {
+        let sessions = Sessions::new();
+        let (token, seat) = sessions.register("room".into(), 1);
+        assert!(sessions.detach(&token, seat.generation));
+        let resumed = sessions.resume(&token).unwrap();
+        assert!(!sessions.expire(&token, seat.generation));
+        assert!(!sessions.expire(&token, resumed.generation));
+        assert!(sessions.resume(&token).is_some());
+    }
+
+    #[test]
+    fn closed_session_cannot_be_resumed() {
+        let sessions = Sessions::new();
+        let (token, seat) = sessions.register("room".into(), 1);
+        let resumed = sessions.resume(&token).unwrap();
+        assert!(!sessions.close(&token, seat.generation));
+        assert!(sessions.close(&token, resumed.generation));
+        assert!(sessions.resume(&token).is_none());
+    }
+}
@@ -0,0 +1,83 @@
+//! HTTPS termination with rustls.
+
+use std::{
+    future::Future,
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long open connections are given to close on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves HTTPS until the shutdown signal.
+///
+/// Fails when the certificate cannot be loaded, or the listener cannot be
+/// served.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: TlsConfig,
+    shutdown_signal: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .map_err(|error| {
+            io::Error::new(error.kind(), format!("invalid certificate or key: {error}"))
+        })?;
+    tokio::spawn(reload(rustls_config.clone(), tls));
+
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal.await;
+        shutdown_handle.graceful_shutdown(Some(SHUTDOWN_TIMEOUT));
+    });
+
+    axum_server::from_tcp_rustls(listener.into_std()?, rustls_config)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await
+}
+
+/// Reloads the certificate whenever its files change, such as when it is
+/// renewed. An invalid certificate is logged, and the previous one is kept.
+async fn reload(rustls_config: RustlsConfig, tls: TlsConfig) {
+    let mut modified = last_modified(&tls);
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        let last_modified = last_modified(&tls);
+        if last_modified == modified {
+            continue;
+        }
+        modified = last_modified;
+
+        match rustls_config
+            .reload_from_pem_file(&tls.cert, &tls.key)
+            .await
+        {
+            Ok(()) => info!("Reloaded the TLS certificate."),
+            Err(error) => warn!("Failed to reload the TLS certificate: {error}"),
+        }
+    }
+}
+
+/// Returns the last time the certificate or the key was modified.
+fn last_modified(tls: &TlsConfig) -> Option<SystemTime> {
+    let modified = |path: &Path| {
+        path.metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    modified(&tls.cert).max(modified(&tls.key))
+}
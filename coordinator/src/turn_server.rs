@@ -0,0 +1,260 @@
+//! Embedded STUN and TURN server.
+//!
+//! Peers reach it over UDP or TCP on the same port, and authenticate with the
+//! ephemeral credentials minted by the coordinator for every session. Relays
+//! are always allocated over UDP.
+
+use std::{
+    any::Any,
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, UdpSocket,
+    },
+    sync::{watch, Mutex},
+};
+use tracing::{debug, info, warn};
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_static::RelayAddressGeneratorStatic,
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+};
+use webrtc_util::{vnet::net::Net, Conn};
+
+use crate::config::{turn_password, Config, TurnServerConfig};
+
+/// Realm of the embedded TURN server.
+const REALM: &str = "meeting.rs";
+
+/// Size of the STUN message header, whose length field excludes it.
+const STUN_HEADER_SIZE: usize = 20;
+
+/// Size of the TURN `ChannelData` message header.
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+
+/// Largest message accepted over TCP.
+const MAX_MESSAGE_SIZE: usize = 1600;
+
+/// Binds and spawns the STUN and TURN server.
+///
+/// Returns the configuration handing it to peers.
+pub async fn spawn(mut config: Config) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let TurnServerConfig {
+        listen_addr,
+        public_ip,
+    } = config.turn_server;
+    let public_ip = public_ip.ok_or("the public IP address of the TURN server is not set")?;
+
+    let udp_socket = UdpSocket::bind(listen_addr).await?;
+    let tcp_listener = TcpListener::bind(listen_addr).await?;
+    info!(
+        "STUN and TURN server listening on {}",
+        udp_socket.local_addr()?
+    );
+
+    let secret = config
+        .ice
+        .embed_turn_server(SocketAddr::new(public_ip, listen_addr.port()));
+    let auth_handler = Arc::new(SecretAuthHandler { secret });
+    let udp_server = Server::new(server_config(
+        Arc::new(udp_socket),
+        listen_addr,
+        public_ip,
+        auth_handler.clone(),
+    ))
+    .await?;
+    tokio::spawn(async move {
+        // The UDP server must be kept alive, as dropping it stops it.
+        let _udp_server = udp_server;
+        run(tcp_listener, listen_addr, public_ip, auth_handler).await;
+    });
+    Ok(config)
+}
+
+/// Serves the TCP connections until the process ends.
+async fn run(
+    tcp_listener: TcpListener,
+    listen_addr: SocketAddr,
+    public_ip: IpAddr,
+    auth_handler: Arc<SecretAuthHandler>,
+) {
+    loop {
+        let stream = match tcp_listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                warn!("TURN server failed to accept a TCP connection: {error}");
+                continue;
+            }
+        };
+        let Ok(conn) = TcpConn::new(stream) else {
+            continue;
+        };
+        let auth_handler = auth_handler.clone();
+        tokio::spawn(async move {
+            let mut closed = conn.closed.subscribe();
+            let config = server_config(Arc::new(conn), listen_addr, public_ip, auth_handler);
+            let server = match Server::new(config).await {
+                Ok(server) => server,
+                Err(error) => {
+                    warn!("TURN server failed to serve a TCP connection: {error}");
+                    return;
+                }
+            };
+            // Each TCP connection gets its own server, stopped along with it.
+            let _ = closed.wait_for(|closed| *closed).await;
+            let _ = server.close().await;
+        });
+    }
+}
+
+fn server_config(
+    conn: Arc<dyn Conn + Send + Sync>,
+    listen_addr: SocketAddr,
+    public_ip: IpAddr,
+    auth_handler: Arc<SecretAuthHandler>,
+) -> ServerConfig {
+    ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                relay_address: public_ip,
+                address: listen_addr.ip().to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: REALM.into(),
+        auth_handler,
+        channel_bind_timeout: Duration::from_secs(0),
+        alloc_close_notify: None,
+    }
+}
+
+/// Authenticates the credentials minted with the TURN secret, as long as they
+/// are not expired.
+struct SecretAuthHandler {
+    secret: String,
+}
+
+impl AuthHandler for SecretAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, turn::Error> {
+        let expiry = username
+            .split(':')
+            .next()
+            .and_then(|expiry| expiry.parse().ok())
+            .map(Duration::from_secs)
+            .ok_or_else(|| turn::Error::Other(format!("Invalid username {username}")))?;
+        if expiry < SystemTime::now().duration_since(UNIX_EPOCH).unwrap() {
+            debug!("Rejected expired TURN username {username} from {src_addr}");
+            return Err(turn::Error::Other(format!("Expired username {username}")));
+        }
+
+        let password = turn_password(&self.secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+/// TCP connection of a single client, framing STUN and `ChannelData`
+/// messages as datagrams.
+struct TcpConn {
+    reader: Mutex<OwnedReadHalf>,
+    writer: Mutex<OwnedWriteHalf>,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    closed: watch::Sender<bool>,
+}
+
+impl TcpConn {
+    fn new(stream: TcpStream) -> std::io::Result<TcpConn> {
+        let local_addr = stream.local_addr()?;
+        let remote_addr = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        Ok(TcpConn {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            local_addr,
+            remote_addr,
+            closed: watch::Sender::new(false),
+        })
+    }
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc_util::Result<()> {
+        Err(webrtc_util::Error::Other("Already connected".into()))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    /// Reads the next message, whose length is in the same place in the
+    /// headers of STUN and `ChannelData` messages.
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        let mut reader = self.reader.lock().await;
+        let mut header = [0; CHANNEL_DATA_HEADER_SIZE];
+        reader.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        // STUN messages start with two zero bits, and `ChannelData` messages
+        // are padded to 4 bytes over TCP.
+        let size = if header[0] & 0xC0 == 0 {
+            STUN_HEADER_SIZE + length
+        } else {
+            CHANNEL_DATA_HEADER_SIZE + length.next_multiple_of(4)
+        };
+        if size > buf.len().min(MAX_MESSAGE_SIZE) {
+            return Err(webrtc_util::Error::Other(format!(
+                "Message of {size} bytes is too large"
+            )));
+        }
+
+        buf[..CHANNEL_DATA_HEADER_SIZE].copy_from_slice(&header);
+        reader
+            .read_exact(&mut buf[CHANNEL_DATA_HEADER_SIZE..size])
+            .await?;
+        Ok((size, self.remote_addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.writer.lock().await.write_all(buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> webrtc_util::Result<usize> {
+        self.send(buf).await
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.closed.send_replace(true);
+        let _ = self.writer.lock().await.shutdown().await;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
@@ -0,0 +1,118 @@
+//! Runs the NATS storage against a local nats-server with JetStream enabled,
+//! at `NATS_URL` or else on the default port.
+//!
+//! Run `just test-nats` with the server started by `nats-server -js`.
+#![cfg(feature = "nats")]
+
+use std::{env, time::Duration};
+
+use coordinator::db::{NatsDb, Storage};
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+async fn connect() -> NatsDb {
+    let url = env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".into());
+    NatsDb::connect(&url, Duration::from_secs(60))
+        .await
+        .expect("a nats-server with JetStream should be running")
+}
+
+/// A key unique to a test run, so that runs do not see each other. Spaces and
+/// dots are not allowed in NATS subjects.
+fn key(name: &str) -> String {
+    format!("test {}.*>:{name}", Uuid::new_v4().simple())
+}
+
+#[tokio::test]
+#[ignore = "needs a local nats-server"]
+async fn values() {
+    let db = connect().await;
+    let key = key("room");
+
+    assert_eq!(db.get(&key).await.unwrap(), None);
+    assert!(db
+        .compare_and_set(&key, Some("a".into()), None)
+        .await
+        .unwrap());
+    assert!(!db
+        .compare_and_set(&key, Some("b".into()), None)
+        .await
+        .unwrap());
+    let (value, revision) = db.get(&key).await.unwrap().unwrap();
+    assert_eq!(value, "a");
+
+    assert!(db
+        .compare_and_set(&key, Some("b".into()), Some(revision))
+        .await
+        .unwrap());
+    assert!(!db
+        .compare_and_set(&key, Some("c".into()), Some(revision))
+        .await
+        .unwrap());
+    let (value, next) = db.get(&key).await.unwrap().unwrap();
+    assert_eq!(value, "b");
+
+    assert!(!db
+        .compare_and_set(&key, None, Some(revision))
+        .await
+        .unwrap());
+    assert!(db.compare_and_set(&key, None, Some(next)).await.unwrap());
+    assert_eq!(db.get(&key).await.unwrap(), None);
+    assert_eq!(db.delete(&key).await.unwrap(), 0);
+
+    assert!(db
+        .compare_and_set(&key, Some("a".into()), None)
+        .await
+        .unwrap());
+    assert_eq!(db.delete(&key).await.unwrap(), 1);
+}
+
+#[tokio::test]
+#[ignore = "needs a local nats-server"]
+async fn values_are_shared_between_coordinators() {
+    let (a, b) = (connect().await, connect().await);
+    let key = key("room");
+
+    // Concurrent updates are not lost.
+    let increments = (0..10).map(|i| {
+        let db = if i % 2 == 0 { a.clone() } else { b.clone() };
+        let key = key.clone();
+        tokio::spawn(async move {
+            loop {
+                let (value, revision) = match db.get(&key).await.unwrap() {
+                    Some((value, revision)) => (value.parse::<u32>().unwrap(), Some(revision)),
+                    None => (0, None),
+                };
+                let value = value + 1;
+                if db
+                    .compare_and_set(&key, Some(value.to_string()), revision)
+                    .await
+                    .unwrap()
+                {
+                    return value;
+                }
+            }
+        })
+    });
+    let mut values = futures_util::future::join_all(increments)
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, (1..=10).collect::<Vec<_>>());
+    a.delete(&key).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a local nats-server"]
+async fn channels_are_shared_between_coordinators() {
+    let (a, b) = (connect().await, connect().await);
+    let channel = key("room");
+
+    let mut first = a.subscribe(&channel).await.unwrap();
+    let mut second = b.subscribe(&channel).await.unwrap();
+    b.publish(&channel, "hello".into()).await.unwrap();
+    assert_eq!(first.next().await.unwrap(), "hello");
+    assert_eq!(second.next().await.unwrap(), "hello");
+}
@@ -0,0 +1,142 @@
+//! Runs the Redis storage against a local redis-server, at `REDIS_URL` or else
+//! on the default port.
+//!
+//! Run `just test-redis` with the server started.
+#![cfg(feature = "redis")]
+
+use std::{env, time::Duration};
+
+use coordinator::db::{RedisDb, Storage};
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+fn url() -> String {
+    env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into())
+}
+
+async fn connect() -> RedisDb {
+    RedisDb::connect(&url())
+        .await
+        .expect("a redis-server should be running")
+}
+
+/// A key unique to a test run, so that runs do not see each other.
+fn key(name: &str) -> String {
+    format!("test-{}:{name}", Uuid::new_v4().simple())
+}
+
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn values() {
+    let db = connect().await;
+    let key = key("room");
+
+    assert_eq!(db.get(&key).await.unwrap(), None);
+    assert!(db
+        .compare_and_set(&key, Some("a".into()), None)
+        .await
+        .unwrap());
+    assert!(!db
+        .compare_and_set(&key, Some("b".into()), None)
+        .await
+        .unwrap());
+    let (value, revision) = db.get(&key).await.unwrap().unwrap();
+    assert_eq!(value, "a");
+
+    assert!(db
+        .compare_and_set(&key, Some("b".into()), Some(revision))
+        .await
+        .unwrap());
+    assert!(!db
+        .compare_and_set(&key, Some("c".into()), Some(revision))
+        .await
+        .unwrap());
+    let (value, next) = db.get(&key).await.unwrap().unwrap();
+    assert_eq!(value, "b");
+    assert_ne!(next, revision);
+
+    assert!(!db
+        .compare_and_set(&key, None, Some(revision))
+        .await
+        .unwrap());
+    assert!(db.compare_and_set(&key, None, Some(next)).await.unwrap());
+    assert_eq!(db.get(&key).await.unwrap(), None);
+    assert_eq!(db.delete(&key).await.unwrap(), 0);
+
+    // A value set again never gets a revision back.
+    assert!(db
+        .compare_and_set(&key, Some("a".into()), None)
+        .await
+        .unwrap());
+    let (_, again) = db.get(&key).await.unwrap().unwrap();
+    assert!(again > next);
+    assert_eq!(db.delete(&key).await.unwrap(), 1);
+}
+
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn values_expire() {
+    let db = connect().await;
+    let key = key("room");
+    assert!(!db.expire(&key, Duration::from_secs(60)).await.unwrap());
+    db.compare_and_set(&key, Some("a".into()), None)
+        .await
+        .unwrap();
+    assert!(db.expire(&key, Duration::from_secs(60)).await.unwrap());
+    // Setting the value keeps its time to live.
+    let (_, revision) = db.get(&key).await.unwrap().unwrap();
+    db.compare_and_set(&key, Some("b".into()), Some(revision))
+        .await
+        .unwrap();
+
+    let mut conn = redis::Client::open(url())
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+        .unwrap();
+    let ttl: i64 = redis::cmd("TTL")
+        .arg(format!("meeting:{key}"))
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    assert!(
+        (1..=60).contains(&ttl),
+        "the value should expire in a minute, its TTL is {ttl}"
+    );
+    db.delete(&key).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn channels_are_shared_between_coordinators() {
+    let (a, b) = (connect().await, connect().await);
+    let channel = key("room");
+
+    let mut first = a.subscribe(&channel).await.unwrap();
+    let mut second = a.subscribe(&channel).await.unwrap();
+    // Every coordinator subscribes once, however many local subscribers.
+    assert_eq!(b.publish(&channel, "hello".into()).await.unwrap(), 1);
+    assert_eq!(first.next().await.unwrap(), "hello");
+    assert_eq!(second.next().await.unwrap(), "hello");
+
+    let mut third = b.subscribe(&channel).await.unwrap();
+    assert_eq!(a.publish(&channel, "world".into()).await.unwrap(), 2);
+    assert_eq!(third.next().await.unwrap(), "world");
+}
+
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn channels_are_unsubscribed_with_their_last_subscriber() {
+    let db = connect().await;
+    let channel = key("room");
+
+    let first = db.subscribe(&channel).await.unwrap();
+    let second = db.subscribe(&channel).await.unwrap();
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(db.publish(&channel, "hello".into()).await.unwrap(), 1);
+
+    drop(second);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(db.publish(&channel, "hello".into()).await.unwrap(), 0);
+}
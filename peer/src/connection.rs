@@ -0,0 +1,306 @@
+//! Peer connection to a remote participant.
+//!
+//! Either side may offer at any time, so connections follow the WebRTC
+//! "perfect negotiation" pattern: on offer collisions the polite side rolls
+//! back its own offer, while the impolite side ignores the remote one.
+
+use std::{cell::Cell, rc::Rc};
+
+use futures::{channel::mpsc::Sender, SinkExt};
+use gloo_console::log;
+use js_sys::{Array, Object, Reflect};
+use protocol::{Envelope, Event, IceCandidate, IceServer, ParticipantId, Role};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    MediaStream, RtcConfiguration, RtcIceCandidate, RtcIceCandidateInit, RtcIceConnectionState,
+    RtcOfferOptions, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSdpType,
+    RtcSessionDescriptionInit, RtcSignalingState, RtcTrackEvent,
+};
+
+use crate::media;
+
+/// Maximum number of consecutive ICE restarts before giving up on a
+/// connection.
+const MAX_ICE_RESTARTS: u32 = 3;
+
+pub(crate) struct Connection {
+    pub(crate) pc: RtcPeerConnection,
+    remote: ParticipantId,
+    /// Role of this peer on the connection, which decides whether it is polite.
+    role: Role,
+    tx: Sender<Envelope>,
+    /// Whether an offer is being created and set as local description.
+    making_offer: Cell<bool>,
+    /// Whether the last offer of the remote participant was ignored because
+    /// of a collision.
+    ignore_offer: Cell<bool>,
+    /// Number of ICE restarts since the connection was last established.
+    ice_restarts: Cell<u32>,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        remote: ParticipantId,
+        role: Role,
+        ice_servers: &[IceServer],
+        tx: Sender<Envelope>,
+    ) -> Result<Rc<Connection>, JsValue> {
+        let pc = peer_connection(ice_servers)?;
+        log!("pc created for", remote, "as", role.to_string());
+
+        let connection = Rc::new(Connection {
+            pc,
+            remote,
+            role,
+            tx,
+            making_offer: Cell::new(false),
+            ignore_offer: Cell::new(false),
+            ice_restarts: Cell::new(0),
+        });
+        onicecandidate(&connection);
+        onconnectionstatechange(&connection);
+        onnegotiationneeded(&connection);
+        ontrack(&connection);
+        Ok(connection)
+    }
+
+    async fn send(&self, event: Event) {
+        self.tx
+            .clone()
+            .send(Envelope::to(self.remote, event))
+            .await
+            .unwrap();
+    }
+
+    /// Creates an offer, sets it as local description and sends it. An ICE
+    /// restart offer gathers new candidates, such as after a network change.
+    async fn offer(&self, ice_restart: bool) -> Result<(), JsValue> {
+        self.making_offer.set(true);
+        let offer_sdp = async {
+            let options = RtcOfferOptions::new();
+            options.set_ice_restart(ice_restart);
+            let offer =
+                JsFuture::from(self.pc.create_offer_with_rtc_offer_options(&options)).await?;
+            self.set_local_description(RtcSdpType::Offer, &offer).await
+        }
+        .await;
+        self.making_offer.set(false);
+
+        self.send(Event::Offer(offer_sdp?)).await;
+        log!("sent an offer to", self.remote);
+        Ok(())
+    }
+
+    /// Creates an answer to the remote offer, sets it as local description
+    /// and sends it.
+    pub(crate) async fn answer(&self) -> Result<(), JsValue> {
+        let answer = JsFuture::from(self.pc.create_answer()).await?;
+        let answer_sdp = self
+            .set_local_description(RtcSdpType::Answer, &answer)
+            .await?;
+
+        self.send(Event::Answer(answer_sdp)).await;
+        log!("sent an answer to", self.remote);
+        Ok(())
+    }
+
+    async fn set_local_description(
+        &self,
+        sdp_type: RtcSdpType,
+        description: &JsValue,
+    ) -> Result<String, JsValue> {
+        let sdp = Reflect::get(description, &JsValue::from_str("sdp"))?
+            .as_string()
+            .unwrap_or_default();
+
+        let description_obj = RtcSessionDescriptionInit::new(sdp_type);
+        description_obj.set_sdp(&sdp);
+        JsFuture::from(self.pc.set_local_description(&description_obj)).await?;
+        log!("pc: state:", self.pc.signaling_state());
+        Ok(sdp)
+    }
+
+    /// Sets a description of the remote participant.
+    ///
+    /// Returns whether it was set, as the impolite side ignores colliding
+    /// offers, and answers that arrive without a pending local offer are
+    /// stale.
+    pub(crate) async fn set_remote_description(
+        &self,
+        sdp_type: RtcSdpType,
+        sdp: &str,
+    ) -> Result<bool, JsValue> {
+        let signaling_state = self.pc.signaling_state();
+        let offer_collision = sdp_type == RtcSdpType::Offer
+            && (self.making_offer.get() || signaling_state != RtcSignalingState::Stable);
+
+        self.ignore_offer
+            .set(!self.role.is_polite() && offer_collision);
+        if self.ignore_offer.get() {
+            log!("ignored a colliding offer from", self.remote);
+            return Ok(false);
+        }
+        if sdp_type == RtcSdpType::Answer && signaling_state != RtcSignalingState::HaveLocalOffer {
+            log!("ignored a stale answer from", self.remote);
+            return Ok(false);
+        }
+        if offer_collision && signaling_state == RtcSignalingState::HaveLocalOffer {
+            // Roll back our own offer to accept the remote one.
+            let rollback = RtcSessionDescriptionInit::new(RtcSdpType::Rollback);
+            JsFuture::from(self.pc.set_local_description(&rollback)).await?;
+            log!("rolled back the offer to", self.remote);
+        }
+
+        let description_obj = RtcSessionDescriptionInit::new(sdp_type);
+        description_obj.set_sdp(sdp);
+        JsFuture::from(self.pc.set_remote_description(&description_obj)).await?;
+        log!("pc: state:", self.pc.signaling_state());
+        Ok(true)
+    }
+
+    pub(crate) async fn add_ice_candidate(&self, candidate: IceCandidate) -> Result<(), JsValue> {
+        let candidate = RtcIceCandidate::new(&{
+            let rtc_candidate = RtcIceCandidateInit::new("");
+            rtc_candidate.set_candidate(&candidate.candidate);
+            rtc_candidate.set_sdp_m_line_index(candidate.sdp_m_line_index);
+            rtc_candidate.set_sdp_mid(candidate.sdp_mid.as_deref());
+            rtc_candidate
+        })?;
+        let promise = self
+            .pc
+            .add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate));
+        match JsFuture::from(promise).await {
+            // Candidates of an ignored offer are expected to fail.
+            Err(_) if self.ignore_offer.get() => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+fn ontrack(connection: &Rc<Connection>) {
+    let remote = connection.remote;
+    let ontrack_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcTrackEvent| {
+        let remote_stream: MediaStream = ev.streams().at(0).unchecked_into();
+        media::remote_video(remote, &remote_stream.id()).set_src_object(Some(&remote_stream));
+        log!("added remote stream of", remote);
+    });
+    connection
+        .pc
+        .set_ontrack(Some(ontrack_callback.as_ref().unchecked_ref()));
+    ontrack_callback.forget();
+}
+
+/// Restarts ICE when the connection fails, up to `MAX_ICE_RESTARTS` times in
+/// a row, while showing the state of the connection to the user.
+fn onconnectionstatechange(connection: &Rc<Connection>) {
+    let connection_clone = connection.clone();
+    let onconnectionstatechange_callback = Closure::<dyn FnMut()>::new(move || {
+        let connection = connection_clone.clone();
+        let remote = connection.remote;
+        let state = connection.pc.ice_connection_state();
+        log!("pc state of", remote, ":", state);
+        match state {
+            RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                connection.ice_restarts.set(0);
+                media::set_remote_status(remote, None);
+            }
+            RtcIceConnectionState::Disconnected => {
+                media::set_remote_status(remote, Some("Reconnecting…"));
+            }
+            RtcIceConnectionState::Failed => {
+                let ice_restarts = connection.ice_restarts.get() + 1;
+                if ice_restarts > MAX_ICE_RESTARTS {
+                    log!("gave up reconnecting to", remote);
+                    media::set_remote_status(remote, Some("Connection lost"));
+                    return;
+                }
+                connection.ice_restarts.set(ice_restarts);
+                media::set_remote_status(
+                    remote,
+                    Some(&format!(
+                        "Reconnecting… ({ice_restarts}/{MAX_ICE_RESTARTS})"
+                    )),
+                );
+                spawn_local(async move {
+                    if let Err(error) = connection.offer(true).await {
+                        log!("failed to restart ICE:", error);
+                    }
+                });
+            }
+            _ => {}
+        }
+    });
+    connection.pc.set_oniceconnectionstatechange(Some(
+        onconnectionstatechange_callback.as_ref().unchecked_ref(),
+    ));
+    onconnectionstatechange_callback.forget();
+}
+
+/// Sends a new offer whenever the peer connection needs to be renegotiated,
+/// such as when a local stream is added in the middle of the call.
+fn onnegotiationneeded(connection: &Rc<Connection>) {
+    let connection_clone = connection.clone();
+    let onnegotiationneeded_callback = Closure::<dyn FnMut()>::new(move || {
+        let connection = connection_clone.clone();
+        spawn_local(async move {
+            if let Err(error) = connection.offer(false).await {
+                log!("failed to send an offer:", error);
+            }
+        });
+    });
+    connection
+        .pc
+        .set_onnegotiationneeded(Some(onnegotiationneeded_callback.as_ref().unchecked_ref()));
+    onnegotiationneeded_callback.forget();
+}
+
+fn onicecandidate(connection: &Rc<Connection>) {
+    let remote = connection.remote;
+    let tx = connection.tx.clone();
+    let onicecandidate_callback =
+        Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
+            if let Some(candidate) = ev.candidate() {
+                let mut tx = tx.clone();
+                spawn_local(async move {
+                    tx.send(Envelope::to(
+                        remote,
+                        Event::IceCandidate(IceCandidate {
+                            candidate: candidate.candidate(),
+                            sdp_mid: candidate.sdp_mid(),
+                            sdp_m_line_index: candidate.sdp_m_line_index(),
+                        }),
+                    ))
+                    .await
+                    .unwrap();
+                    log!("successfully sent a candidate to", remote);
+                });
+            }
+        });
+    connection
+        .pc
+        .set_onicecandidate(Some(onicecandidate_callback.as_ref().unchecked_ref()));
+    onicecandidate_callback.forget();
+}
+
+fn peer_connection(servers: &[IceServer]) -> Result<RtcPeerConnection, JsValue> {
+    RtcPeerConnection::new_with_configuration(&{
+        let ice_servers = Array::new();
+        for server in servers {
+            let server_entry = Object::new();
+            let urls: Array = server.urls.iter().map(JsValue::from).collect();
+            Reflect::set(&server_entry, &"urls".into(), &urls)?;
+            if let Some(username) = &server.username {
+                Reflect::set(&server_entry, &"username".into(), &username.into())?;
+            }
+            if let Some(credential) = &server.credential {
+                Reflect::set(&server_entry, &"credential".into(), &credential.into())?;
+            }
+            ice_servers.push(&server_entry);
+        }
+
+        let rtc_configuration = RtcConfiguration::new();
+        rtc_configuration.set_ice_servers(&ice_servers);
+        rtc_configuration
+    })
+}
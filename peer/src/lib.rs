@@ -1,226 +1,309 @@
+mod connection;
 mod listener;
 mod media;
+mod signaling;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
 use futures::{
     channel::mpsc::{self, Sender},
-    stream::SplitStream,
-    SinkExt, StreamExt,
+    SinkExt,
 };
 use gloo_console::log;
 use gloo_dialogs::alert;
-use gloo_net::websocket::{futures::WebSocket, Message};
+use gloo_timers::callback::Interval;
 use gloo_utils::window;
-use js_sys::{Array, Error, Object, Reflect};
-use listener::{get_element_by_id, passphrase_listener};
-use protocol::{Event, IceCandidate, Role};
+use js_sys::Date;
+use listener::{passphrase_listener, share_screen_listener, wait_again_listener};
+use protocol::{Codec, Envelope, ErrorCode, Event, IceServer, ParticipantId, Role};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::{spawn_local, JsFuture};
-use web_sys::{
-    HtmlMediaElement, RtcConfiguration, RtcIceCandidate, RtcIceCandidateInit,
-    RtcIceConnectionState, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSdpType,
-    RtcSessionDescriptionInit, RtcTrackEvent,
-};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{MediaStream, RtcPeerConnection, RtcSdpType};
+
+use crate::connection::Connection;
 
 #[wasm_bindgen(start)]
 pub async fn main() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
 
-    let ws = WebSocket::open(&ws_uri()?).map_err(|err| Error::new(&err.to_string()))?;
-    log!("WebSocket Connected.");
-    let (mut write, read) = ws.split();
+    let (tx, rx) = mpsc::channel::<Envelope>(10);
+
+    let local_stream = media::init().await?;
+    let meeting = Meeting::new(local_stream, tx);
+
+    // Signaling task.
+    let meeting_clone = meeting.clone();
+    spawn_local(async move { signaling::run(&meeting_clone, rx).await });
+
+    passphrase_listener(meeting.clone());
+    share_screen_listener(meeting);
+    wait_again_listener();
+
+    Ok(())
+}
+
+/// State of this peer in the meeting, shared by all event handlers.
+pub(crate) struct Meeting {
+    tx: Sender<Envelope>,
+    /// Codec of the current connection to the coordinator.
+    codec: Cell<Codec>,
+    /// Passphrase of the meeting, once entered.
+    passphrase: RefCell<Option<String>>,
+    /// Token to resume the session after reconnecting, once joined.
+    resume_token: RefCell<Option<String>>,
+    /// Whether the meeting ended, so that the coordinator is not reconnected.
+    ended: Cell<bool>,
+    /// STUN and TURN servers handed by the coordinator.
+    ice_servers: RefCell<Vec<IceServer>>,
+    /// Local media streams, sent to every other participant.
+    local_streams: RefCell<Vec<MediaStream>>,
+    /// Connections to every other participant.
+    peers: RefCell<HashMap<ParticipantId, Rc<Connection>>>,
+    /// Countdown of the wait for other participants, while alone.
+    waiting: RefCell<Option<Interval>>,
+}
+
+impl Meeting {
+    fn new(local_stream: MediaStream, tx: Sender<Envelope>) -> Rc<Meeting> {
+        Rc::new(Meeting {
+            tx,
+            codec: Cell::default(),
+            passphrase: RefCell::default(),
+            resume_token: RefCell::default(),
+            ended: Cell::new(false),
+            ice_servers: RefCell::default(),
+            local_streams: RefCell::new(vec![local_stream]),
+            peers: RefCell::default(),
+            waiting: RefCell::default(),
+        })
+    }
+
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec.get()
+    }
 
-    let (tx, mut rx) = mpsc::channel(10);
+    pub(crate) fn set_codec(&self, codec: Codec) {
+        self.codec.set(codec);
+    }
 
-    // Write task.
-    spawn_local(async move {
-        while let Some(msg) = rx.next().await {
-            write.send(Message::Text(msg)).await.unwrap();
+    pub(crate) fn has_ended(&self) -> bool {
+        self.ended.get()
+    }
+
+    /// Joins the meeting of a passphrase.
+    pub(crate) async fn join(&self, passphrase: String) {
+        *self.passphrase.borrow_mut() = Some(passphrase.clone());
+        self.tx
+            .clone()
+            .send(Event::Passphrase(passphrase).into())
+            .await
+            .unwrap();
+    }
+
+    /// Returns the event to join the meeting again after reconnecting to the
+    /// coordinator, if this peer was in it.
+    pub(crate) fn rejoin(&self) -> Option<Envelope> {
+        if let Some(token) = self.resume_token.borrow().clone() {
+            return Some(Event::Resume(token).into());
         }
-    });
+        self.passphrase
+            .borrow()
+            .clone()
+            .map(|passphrase| Event::Passphrase(passphrase).into())
+    }
 
-    let pc = peer_connection()?;
-    log!("pc created: state:", pc.signaling_state());
-    onicecandidate(&pc, tx.clone());
-    onconnectionstatechange(&pc, tx.clone());
-    ontrack(&pc);
-    media::init(&pc).await?;
+    /// Shows how long this peer still waits for other participants, before
+    /// the coordinator gives up.
+    fn wait(&self, timeout_secs: u64) {
+        let deadline = Date::now() + timeout_secs as f64 * 1000.0;
+        let show = move || {
+            let left = ((deadline - Date::now()) / 1000.0).ceil().max(0.0) as u64;
+            media::set_meeting_status(Some(&format!(
+                "Waiting for the other participant ({}:{:02})",
+                left / 60,
+                left % 60
+            )));
+        };
+        show();
+        *self.waiting.borrow_mut() = Some(Interval::new(1_000, show));
+    }
 
-    // Read task.
-    handle_events(pc, tx.clone(), read);
+    /// Stops showing the wait for other participants, and that they left.
+    fn stop_waiting(&self) {
+        media::show_peer_left(false);
+        if self.waiting.borrow_mut().take().is_some() {
+            media::set_meeting_status(None);
+        }
+    }
 
-    passphrase_listener(tx);
+    /// Closes the connection to a participant who left.
+    fn disconnect(&self, remote: ParticipantId) {
+        let connection = self.peers.borrow_mut().remove(&remote);
+        if let Some(connection) = connection {
+            connection.pc.close();
+        }
+        media::remove_remote(remote);
+    }
 
-    Ok(())
-}
+    /// Closes the connections to every other participant.
+    fn disconnect_all(&self) {
+        let remotes = self.peers.borrow().keys().copied().collect::<Vec<_>>();
+        for remote in remotes {
+            self.disconnect(remote);
+        }
+    }
+
+    fn peer(&self, remote: ParticipantId) -> Option<Rc<Connection>> {
+        self.peers.borrow().get(&remote).cloned()
+    }
+
+    /// Returns the connection to a remote participant, creating it with the
+    /// given role if it doesn't exist yet.
+    fn connect(&self, remote: ParticipantId, role: Role) -> Result<Rc<Connection>, JsValue> {
+        if let Some(connection) = self.peer(remote) {
+            return Ok(connection);
+        }
+
+        let connection =
+            Connection::new(remote, role, &self.ice_servers.borrow(), self.tx.clone())?;
+        self.peers.borrow_mut().insert(remote, connection.clone());
+        Ok(connection)
+    }
+
+    /// Adds the local streams to a peer connection, unless it's already done.
+    fn add_local_streams(&self, pc: &RtcPeerConnection) {
+        if pc.get_senders().length() > 0 {
+            return;
+        }
+        for stream in self.local_streams.borrow().iter() {
+            media::add_tracks(pc, stream);
+        }
+    }
 
-fn handle_events(pc: RtcPeerConnection, mut tx: Sender<String>, mut read: SplitStream<WebSocket>) {
-    spawn_local(async move {
-        while let Some(Ok(Message::Text(msg))) = read.next().await {
-            let event: Event = serde_json::from_str(&msg).unwrap();
-            match event {
-                Event::Role(role) => {
-                    log!("this peer's role is:", role.to_string());
-                    if let Role::Initiator = role {
-                        // Send offer.
-                        let offer = JsFuture::from(pc.create_offer()).await.unwrap();
-                        let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
-                            .unwrap()
-                            .as_string()
-                            .unwrap();
-
-                        let offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-                        offer_obj.set_sdp(&offer_sdp);
-                        let sld_promise = pc.set_local_description(&offer_obj);
-                        JsFuture::from(sld_promise).await.unwrap();
-                        log!("pc: state:", pc.signaling_state());
-
-                        tx.send(serde_json::to_string(&Event::Offer(offer_sdp)).unwrap())
-                            .await
-                            .unwrap();
-                        log!("sent an offer.");
+    /// Shares a new local stream with every participant, which renegotiates
+    /// every peer connection.
+    pub(crate) fn add_stream(&self, stream: MediaStream) {
+        for connection in self.peers.borrow().values() {
+            media::add_tracks(&connection.pc, &stream);
+        }
+        self.local_streams.borrow_mut().push(stream);
+    }
+
+    /// Handles an event of the coordinator.
+    ///
+    /// Returns whether to keep the connection to the coordinator.
+    pub(crate) async fn on_event(&self, envelope: Envelope) -> bool {
+        match envelope.event {
+            Event::Welcome(welcome) => {
+                log!(
+                    "connected to a coordinator of protocol version",
+                    welcome.protocol_version
+                );
+                self.set_codec(welcome.codec());
+                *self.ice_servers.borrow_mut() = welcome.ice_servers;
+            }
+            Event::Joined(joined) => {
+                log!("joined the meeting as participant", joined.id);
+                *self.resume_token.borrow_mut() = Some(joined.resume_token);
+            }
+            Event::Waiting(waiting) => {
+                log!("waiting for other participants.");
+                self.wait(waiting.timeout_secs);
+            }
+            Event::Error(ErrorCode::ResumeFailed) => {
+                // Join the meeting again as a new participant.
+                log!("failed to resume the session.");
+                self.resume_token.borrow_mut().take();
+                self.stop_waiting();
+                self.disconnect_all();
+                return false;
+            }
+            Event::Error(error) => {
+                log!("An error occurred:", error.to_string());
+                self.ended.set(true);
+                self.stop_waiting();
+                match error {
+                    // This page is a stale cached bundle, fetch the current one.
+                    ErrorCode::UnsupportedVersion { .. } => {
+                        alert("This page is outdated and will be reloaded.");
+                        let _ = window().location().reload();
+                    }
+                    ErrorCode::RoomFull => {
+                        alert("This meeting is already full, please try another passphrase.");
                     }
+                    error => alert(&error.to_string()),
                 }
-                Event::Offer(offer) => {
-                    log!("received offer");
-                    let offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-                    offer_obj.set_sdp(&offer);
-                    let srd_promise = pc.set_remote_description(&offer_obj);
-                    JsFuture::from(srd_promise).await.unwrap();
-                    log!("pc: state:", pc.signaling_state());
-
-                    let answer = JsFuture::from(pc.create_answer()).await.unwrap();
-                    let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))
-                        .unwrap()
-                        .as_string()
-                        .unwrap();
-
-                    let answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-                    answer_obj.set_sdp(&answer_sdp);
-                    let sld_promise = pc.set_local_description(&answer_obj);
-                    JsFuture::from(sld_promise).await.unwrap();
-                    log!("pc: state:", pc.signaling_state());
-
-                    tx.send(serde_json::to_string(&Event::Answer(answer_sdp)).unwrap())
-                        .await
-                        .unwrap();
-                    log!("sent an answer.");
+                return false;
+            }
+            event => {
+                // Signaling events always come from a remote participant.
+                let Some(remote) = envelope.from else {
+                    log!(
+                        "ignored a signaling event without sender:",
+                        format!("{event:?}")
+                    );
+                    return true;
+                };
+                if let Err(error) = self.on_signaling(remote, event).await {
+                    log!("Signaling with", remote, "failed:", error);
                 }
-                Event::Answer(answer) => {
-                    log!("received answer");
-                    let answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-                    answer_obj.set_sdp(&answer);
-                    let srd_promise = pc.set_remote_description(&answer_obj);
-                    JsFuture::from(srd_promise).await.unwrap();
-                    log!("pc: state:", pc.signaling_state());
+            }
+        }
+        true
+    }
+
+    /// Handles a signaling event of a remote participant.
+    async fn on_signaling(&self, remote: ParticipantId, event: Event) -> Result<(), JsValue> {
+        match event {
+            Event::PeerJoined(remote) => {
+                log!("participant joined:", remote);
+                self.stop_waiting();
+                // Adding the local tracks triggers the negotiation.
+                let connection = self.connect(remote, Role::Initiator)?;
+                self.add_local_streams(&connection.pc);
+            }
+            Event::PeerLeft(remote) => {
+                log!("participant left:", remote);
+                self.disconnect(remote);
+                // Offer to wait for them to join again.
+                if self.peers.borrow().is_empty() {
+                    media::show_peer_left(true);
+                }
+            }
+            Event::Offer(offer) => {
+                log!("received offer from", remote);
+                self.stop_waiting();
+                let connection = self.connect(remote, Role::Responder)?;
+                if connection
+                    .set_remote_description(RtcSdpType::Offer, &offer)
+                    .await?
+                {
+                    // Adding the local tracks only now lets them reuse the
+                    // transceivers of the offer.
+                    self.add_local_streams(&connection.pc);
+                    connection.answer().await?;
                 }
-                Event::IceCandidate(candidate) => {
-                    log!("received a candidate.");
-                    let candidate = RtcIceCandidate::new(&{
-                        let rtc_candidate = RtcIceCandidateInit::new("");
-                        rtc_candidate.set_candidate(&candidate.candidate);
-                        rtc_candidate.set_sdp_m_line_index(candidate.sdp_m_line_index);
-                        rtc_candidate.set_sdp_mid(candidate.sdp_mid.as_deref());
-                        rtc_candidate
-                    })
-                    .unwrap();
-                    let promise = pc.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate));
-                    JsFuture::from(promise).await.unwrap();
+            }
+            Event::Answer(answer) => {
+                log!("received answer from", remote);
+                if let Some(connection) = self.peer(remote) {
+                    connection
+                        .set_remote_description(RtcSdpType::Answer, &answer)
+                        .await?;
                 }
-                Event::Error(error) => {
-                    log!("An error occurred:", &error);
-                    alert(&error);
-                    return;
+            }
+            Event::IceCandidate(candidate) => {
+                log!("received a candidate from", remote);
+                if let Some(connection) = self.peer(remote) {
+                    connection.add_ice_candidate(candidate).await?;
                 }
-                _ => {}
             }
+            _ => {}
         }
-        log!("WebSocket Closed.")
-    });
-}
-
-fn ontrack(pc: &RtcPeerConnection) {
-    let ontrack_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcTrackEvent| {
-        let remote_stream = ev.streams().at(0);
-        get_element_by_id::<HtmlMediaElement>("remote-video")
-            .expect("#remote-video should be an `HtmlVideoElement`")
-            .set_src_object(remote_stream.dyn_ref());
-        log!("added remote stream.");
-    });
-    pc.set_ontrack(Some(ontrack_callback.as_ref().unchecked_ref()));
-    ontrack_callback.forget();
-}
-
-fn onconnectionstatechange(pc: &RtcPeerConnection, tx: Sender<String>) {
-    let pc_clone = pc.clone();
-    let onconnectionstatechange_callback = Closure::<dyn FnMut()>::new(move || {
-        log!("pc state:", pc_clone.ice_connection_state());
-        if matches!(
-            pc_clone.ice_connection_state(),
-            RtcIceConnectionState::Connected | RtcIceConnectionState::Failed
-        ) {
-            let mut tx = tx.clone();
-            spawn_local(async move {
-                tx.send(serde_json::to_string(&Event::CloseConnection).unwrap())
-                    .await
-                    .unwrap();
-            });
-        }
-    });
-    pc.set_oniceconnectionstatechange(Some(
-        onconnectionstatechange_callback.as_ref().unchecked_ref(),
-    ));
-    onconnectionstatechange_callback.forget();
-}
-
-fn onicecandidate(pc: &RtcPeerConnection, tx: Sender<String>) {
-    let onicecandidate_callback =
-        Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
-            if let Some(candidate) = ev.candidate() {
-                let mut tx = tx.clone();
-                spawn_local(async move {
-                    tx.send(
-                        serde_json::to_string(&Event::IceCandidate(IceCandidate {
-                            candidate: candidate.candidate(),
-                            sdp_mid: candidate.sdp_mid(),
-                            sdp_m_line_index: candidate.sdp_m_line_index(),
-                        }))
-                        .unwrap(),
-                    )
-                    .await
-                    .unwrap();
-                    log!("successfully sent a candidate.");
-                });
-            }
-        });
-    pc.set_onicecandidate(Some(onicecandidate_callback.as_ref().unchecked_ref()));
-    onicecandidate_callback.forget();
-}
-
-fn peer_connection() -> Result<RtcPeerConnection, JsValue> {
-    RtcPeerConnection::new_with_configuration(&{
-        let ice_servers = Array::new();
-        let server_entry = Object::new();
-        Reflect::set(
-            &server_entry,
-            &"urls".into(),
-            &"stun:stun.l.google.com:19302".into(),
-        )?;
-        ice_servers.push(&server_entry);
-
-        let rtc_configuration = RtcConfiguration::new();
-        rtc_configuration.set_ice_servers(&ice_servers);
-        rtc_configuration
-    })
-}
-
-fn ws_uri() -> Result<String, JsValue> {
-    let protocol = if window().location().protocol()?.eq("https:") {
-        "wss://"
-    } else {
-        "ws://"
-    };
-    Ok(format!(
-        "{protocol}{}/websocket",
-        window().location().host()?
-    ))
+        Ok(())
+    }
 }
@@ -1,13 +1,19 @@
+use crate::{
+    media::{self, get_display_media},
+    Meeting,
+};
+
+use std::rc::Rc;
+
 use futures::{channel::mpsc::Sender, SinkExt};
 use gloo_console::log;
 use gloo_events::EventListener;
 use gloo_utils::document;
-use protocol::Event;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
 use web_sys::{HtmlButtonElement, HtmlFormElement, HtmlInputElement, MediaStreamTrack};
 
-pub(crate) fn passphrase_listener(mut tx: Sender<String>) {
+pub(crate) fn passphrase_listener(meeting: Rc<Meeting>) {
     let listener = EventListener::once(
         &get_element_by_id::<HtmlFormElement>("passphrase-form")
             .expect("#passphrase-form should be an `HtmlFormElement`"),
@@ -18,9 +24,7 @@ pub(crate) fn passphrase_listener(mut tx: Sender<String>) {
                 .value();
             spawn_local(async move {
                 // Send passphrase.
-                tx.send(serde_json::to_string(&Event::Passphrase(passphrase)).unwrap())
-                    .await
-                    .unwrap();
+                meeting.join(passphrase).await;
                 log!("successfully sent passphrase.");
             });
         },
@@ -28,6 +32,37 @@ pub(crate) fn passphrase_listener(mut tx: Sender<String>) {
     listener.forget();
 }
 
+pub(crate) fn share_screen_listener(meeting: Rc<Meeting>) {
+    let listener = EventListener::new(
+        &get_element_by_id::<HtmlButtonElement>("share-screen")
+            .expect("#share-screen should be an `HtmlButtonElement`"),
+        "click",
+        move |_| {
+            let meeting = meeting.clone();
+            spawn_local(async move {
+                match get_display_media().await {
+                    Ok(stream) => {
+                        meeting.add_stream(stream);
+                        log!("shared screen.");
+                    }
+                    Err(error) => log!("failed to share screen:", error),
+                }
+            });
+        },
+    );
+    listener.forget();
+}
+
+pub(crate) fn wait_again_listener() {
+    let listener = EventListener::new(
+        &get_element_by_id::<HtmlButtonElement>("wait-again")
+            .expect("#wait-again should be an `HtmlButtonElement`"),
+        "click",
+        move |_| media::show_peer_left(false),
+    );
+    listener.forget();
+}
+
 pub(crate) fn track_mute_listener(track: MediaStreamTrack) {
     let element_id = if track.kind() == "audio" {
         "mute-audio"
@@ -7,26 +7,24 @@ use futures::{
     StreamExt,
 };
 use gloo_console::log;
-use gloo_utils::window;
+use gloo_utils::{document, window};
 use js_sys::Array;
+use protocol::ParticipantId;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    DisplayMediaStreamConstraints, HtmlMediaElement, MediaDeviceInfo, MediaDeviceKind,
+    DisplayMediaStreamConstraints, Element, HtmlMediaElement, MediaDeviceInfo, MediaDeviceKind,
     MediaDevices, MediaStream, MediaStreamConstraints, MediaStreamTrack, RtcPeerConnection,
 };
 
-pub(crate) async fn init(pc: &RtcPeerConnection) -> Result<(), JsValue> {
+pub(crate) async fn init() -> Result<MediaStream, JsValue> {
     let (tx, rx) = channel(1);
     sharing_option_listener("option-media".into(), tx.clone());
     sharing_option_listener("option-screen".into(), tx);
-    handle_local_stream(pc, rx).await
+    handle_local_stream(rx).await
 }
 
-async fn handle_local_stream(
-    pc: &RtcPeerConnection,
-    mut rx: Receiver<UserSharingOption>,
-) -> Result<(), JsValue> {
+async fn handle_local_stream(mut rx: Receiver<UserSharingOption>) -> Result<MediaStream, JsValue> {
     // We receive the first message since there will only be one user sharing option.
     let local_stream = match rx.next().await.unwrap() {
         UserSharingOption::Media => {
@@ -55,17 +53,106 @@ async fn handle_local_stream(
     local_stream
         .get_tracks()
         .for_each(&mut |track: JsValue, _, _| {
-            let track = track.dyn_into().unwrap();
-            pc.add_track_0(&track, &local_stream);
-            log!("added a local track.");
-
+            let track: MediaStreamTrack = track.dyn_into().unwrap();
             if track.kind() == "video" {
                 display_local_video(&track);
             }
             track_mute_listener(track);
         });
 
-    Ok(())
+    Ok(local_stream)
+}
+
+/// Adds every track of the local stream to a peer connection.
+pub(crate) fn add_tracks(pc: &RtcPeerConnection, local_stream: &MediaStream) {
+    local_stream
+        .get_tracks()
+        .for_each(&mut |track: JsValue, _, _| {
+            pc.add_track_0(&track.dyn_into().unwrap(), local_stream);
+            log!("added a local track.");
+        });
+}
+
+/// Returns the video element of a remote stream, creating it in the tile of
+/// the remote participant if it doesn't exist yet.
+pub(crate) fn remote_video(remote: ParticipantId, stream_id: &str) -> HtmlMediaElement {
+    let element_id = format!("remote-video-{remote}-{stream_id}");
+    if let Some(video) = document().get_element_by_id(&element_id) {
+        return video
+            .dyn_into()
+            .unwrap_or_else(|_| panic!("#{} should be an `HtmlVideoElement`", element_id));
+    }
+
+    let video: HtmlMediaElement = document()
+        .create_element("video")
+        .unwrap()
+        .dyn_into()
+        .unwrap();
+    video.set_id(&element_id);
+    video.set_class_name("video_play");
+    video.set_autoplay(true);
+    video.set_controls(true);
+    remote_tile(remote).append_child(&video).unwrap();
+    video
+}
+
+/// Shows a status of the meeting above the remote videos, or hides it.
+pub(crate) fn set_meeting_status(status: Option<&str>) {
+    get_element_by_id::<Element>("meeting-status")
+        .unwrap()
+        .set_text_content(status);
+}
+
+/// Shows that the other participants left, along with the choice to wait for
+/// them, or hides it.
+pub(crate) fn show_peer_left(shown: bool) {
+    get_element_by_id::<Element>("peer-left")
+        .unwrap()
+        .set_class_name(if shown {
+            "peer-left shown"
+        } else {
+            "peer-left"
+        });
+}
+
+/// Shows a status over the videos of a remote participant, or hides it.
+pub(crate) fn set_remote_status(remote: ParticipantId, status: Option<&str>) {
+    let tile = remote_tile(remote);
+    let status_element = tile.query_selector(".status").unwrap().unwrap();
+    status_element.set_text_content(status);
+    tile.set_class_name(if status.is_some() {
+        "remote with-status"
+    } else {
+        "remote"
+    });
+}
+
+/// Removes the tile holding the videos of a remote participant.
+pub(crate) fn remove_remote(remote: ParticipantId) {
+    if let Some(tile) = document().get_element_by_id(&format!("remote-{remote}")) {
+        tile.remove();
+    }
+}
+
+/// Returns the tile holding the videos of a remote participant, creating it
+/// in `#remote-videos` if it doesn't exist yet.
+fn remote_tile(remote: ParticipantId) -> Element {
+    let element_id = format!("remote-{remote}");
+    if let Some(tile) = document().get_element_by_id(&element_id) {
+        return tile;
+    }
+
+    let tile = document().create_element("div").unwrap();
+    tile.set_id(&element_id);
+    tile.set_class_name("remote");
+    let status_element = document().create_element("p").unwrap();
+    status_element.set_class_name("status");
+    tile.append_child(&status_element).unwrap();
+    get_element_by_id::<Element>("remote-videos")
+        .unwrap()
+        .append_child(&tile)
+        .unwrap();
+    tile
 }
 
 async fn get_user_media(enable_video: bool, enable_audio: bool) -> Result<MediaStream, JsValue> {
@@ -80,7 +167,7 @@ async fn get_user_media(enable_video: bool, enable_audio: bool) -> Result<MediaS
     ))
 }
 
-async fn get_display_media() -> Result<MediaStream, JsValue> {
+pub(crate) async fn get_display_media() -> Result<MediaStream, JsValue> {
     Ok(MediaStream::from(
         JsFuture::from(media_devices()?.get_display_media_with_constraints(&{
             let display_media_stream_constraints = DisplayMediaStreamConstraints::new();
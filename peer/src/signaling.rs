@@ -0,0 +1,131 @@
+//! Signaling WebSocket to the coordinator, reconnected whenever it drops.
+
+use std::pin::pin;
+
+use futures::{
+    channel::mpsc::Receiver,
+    future::{self, Either},
+    lock::Mutex,
+    SinkExt, StreamExt,
+};
+use gloo_console::log;
+use gloo_net::websocket::{futures::WebSocket, Message};
+use gloo_timers::future::TimeoutFuture;
+use gloo_utils::{document, window};
+use protocol::{Capability, ClientKind, Codec, Envelope, Event, Frame, Hello};
+use wasm_bindgen::prelude::*;
+use web_sys::Url;
+
+use crate::Meeting;
+
+/// Delay before the first reconnection attempt, doubled after every attempt
+/// until the coordinator welcomes this peer again.
+const RECONNECT_DELAY_MS: u32 = 1_000;
+const MAX_RECONNECT_DELAY_MS: u32 = 30_000;
+
+/// Sends the envelopes of `rx` to the coordinator and handles its events,
+/// until the meeting ends.
+pub(crate) async fn run(meeting: &Meeting, rx: Receiver<Envelope>) {
+    // Only the current connection sends envelopes, while the others wait to
+    // be dropped.
+    let rx = Mutex::new(rx);
+    let mut delay = RECONNECT_DELAY_MS;
+    loop {
+        match ws_uri().and_then(|uri| WebSocket::open(&uri).map_err(|err| err.to_string().into())) {
+            Ok(ws) => {
+                log!("WebSocket Connected.");
+                if session(meeting, ws, &rx).await {
+                    delay = RECONNECT_DELAY_MS;
+                }
+            }
+            Err(error) => log!("WebSocket failed to connect:", error),
+        }
+        if meeting.has_ended() {
+            log!("WebSocket Closed.");
+            return;
+        }
+
+        log!("WebSocket Closed, reconnecting in", delay, "ms.");
+        TimeoutFuture::new(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY_MS);
+    }
+}
+
+/// Runs a single connection to the coordinator until it drops.
+///
+/// Returns whether the coordinator welcomed this peer.
+async fn session(meeting: &Meeting, ws: WebSocket, rx: &Mutex<Receiver<Envelope>>) -> bool {
+    let (mut write, mut read) = ws.split();
+
+    // The handshake always happens in JSON, and the negotiated codec is used
+    // from then on. A peer already in the meeting joins it again right away.
+    meeting.set_codec(Codec::Json);
+    let hello = Envelope::from(Event::Hello(Hello::new(
+        ClientKind::Web,
+        vec![Capability::MultiParty, Capability::Cbor],
+    )));
+    for envelope in [Some(hello), meeting.rejoin()].into_iter().flatten() {
+        if write.send(message(Codec::Json, &envelope)).await.is_err() {
+            return false;
+        }
+    }
+
+    // Write task.
+    let write_task = async move {
+        let mut rx = rx.lock().await;
+        while let Some(envelope) = rx.next().await {
+            if let Err(error) = write.send(message(meeting.codec(), &envelope)).await {
+                log!("WebSocket failed to send message:", error.to_string());
+                return;
+            }
+        }
+    };
+
+    // Read task.
+    let read_task = async move {
+        let mut welcomed = false;
+        while let Some(Ok(msg)) = read.next().await {
+            let frame = match msg {
+                Message::Text(text) => Frame::Text(text),
+                Message::Bytes(bytes) => Frame::Binary(bytes),
+            };
+            match frame.decode() {
+                Ok(envelope) => {
+                    welcomed |= matches!(envelope.event, Event::Welcome(_));
+                    if !meeting.on_event(envelope).await {
+                        break;
+                    }
+                }
+                Err(error) => log!("Received an unknown event:", error.to_string()),
+            }
+        }
+        welcomed
+    };
+
+    match future::select(pin!(write_task), pin!(read_task)).await {
+        Either::Left(_) => false,
+        Either::Right((welcomed, _)) => welcomed,
+    }
+}
+
+fn message(codec: Codec, envelope: &Envelope) -> Message {
+    match codec.encode(envelope) {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Bytes(bytes),
+    }
+}
+
+fn ws_uri() -> Result<String, JsValue> {
+    // The coordinator sets the base URL of the page to its base path.
+    let base = match document().base_uri()? {
+        Some(base) => base,
+        None => window().location().href()?,
+    };
+    let url = Url::new_with_base("websocket", &base)?;
+    url.set_protocol(if url.protocol() == "https:" {
+        "wss:"
+    } else {
+        "ws:"
+    });
+    Ok(url.href())
+}
@@ -0,0 +1,126 @@
+//! Wire encodings of envelopes.
+//!
+//! JSON in text frames is the default and is always understood. CBOR in binary
+//! frames is a compact alternative negotiated with `Capability::Cbor` during
+//! the handshake, which itself always happens in JSON.
+
+use crate::Envelope;
+
+/// Encoding used to send envelopes on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Cbor,
+}
+
+/// An encoded envelope, as carried by a WebSocket frame.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    /// JSON in a text frame.
+    Text(String),
+    /// CBOR in a binary frame.
+    Binary(Vec<u8>),
+}
+
+/// Error while decoding a frame.
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Cbor(ciborium::de::Error<std::io::Error>),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            CodecError::Json(error) => write!(f, "Invalid JSON frame: {error}"),
+            CodecError::Cbor(error) => write!(f, "Invalid CBOR frame: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl Codec {
+    pub fn encode(&self, envelope: &Envelope) -> Frame {
+        match self {
+            Codec::Json => Frame::Text(serde_json::to_string(envelope).unwrap()),
+            Codec::Cbor => {
+                let mut buf = Vec::new();
+                // Writing into a `Vec` never fails.
+                ciborium::into_writer(envelope, &mut buf).unwrap();
+                Frame::Binary(buf)
+            }
+        }
+    }
+}
+
+impl Frame {
+    /// Decodes the envelope according to the frame type, whatever the codec
+    /// negotiated, so that frames sent around the handshake are never lost.
+    pub fn decode(&self) -> Result<Envelope, CodecError> {
+        match self {
+            Frame::Text(text) => serde_json::from_str(text).map_err(CodecError::Json),
+            Frame::Binary(bytes) => {
+                ciborium::from_reader(bytes.as_slice()).map_err(CodecError::Cbor)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, IceCandidate};
+
+    fn candidate() -> Envelope {
+        Envelope {
+            from: Some(1),
+            to: Some(2),
+            event: Event::IceCandidate(IceCandidate {
+                candidate: "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host".to_owned(),
+                sdp_mid: Some("0".to_owned()),
+                sdp_m_line_index: Some(0),
+            }),
+        }
+    }
+
+    fn assert_candidate(envelope: Envelope) {
+        let Envelope {
+            from: Some(1),
+            to: Some(2),
+            event: Event::IceCandidate(candidate),
+        } = envelope
+        else {
+            panic!("unexpected envelope: {envelope:?}");
+        };
+        assert_eq!(
+            candidate.candidate,
+            "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host"
+        );
+        assert_eq!(candidate.sdp_mid.as_deref(), Some("0"));
+        assert_eq!(candidate.sdp_m_line_index, Some(0));
+    }
+
+    #[test]
+    fn json_goes_in_text_frames() {
+        let frame = Codec::Json.encode(&candidate());
+        assert!(matches!(frame, Frame::Text(_)));
+        assert_candidate(frame.decode().unwrap());
+    }
+
+    #[test]
+    fn cbor_goes_in_binary_frames() {
+        let frame = Codec::Cbor.encode(&candidate());
+        assert!(matches!(frame, Frame::Binary(_)));
+        assert_candidate(frame.decode().unwrap());
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        let text = Frame::Text("{\"event\":".to_owned());
+        assert!(matches!(text.decode(), Err(CodecError::Json(_))));
+        let binary = Frame::Binary(vec![0xff]);
+        assert!(matches!(binary.decode(), Err(CodecError::Cbor(_))));
+    }
+}
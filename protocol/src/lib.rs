@@ -1,18 +1,252 @@
+mod codec;
+
 use serde::{Deserialize, Serialize};
 
+pub use codec::{Codec, CodecError, Frame};
+
+/// JSON Schema of the wire format, that is of every `Envelope` in JSON.
+#[cfg(feature = "schema")]
+pub fn schema() -> schemars::Schema {
+    schemars::schema_for!(Envelope)
+}
+
+/// Version of the signaling protocol, bumped on every incompatible change.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Identifier assigned by the coordinator to every participant of a meeting.
+pub type ParticipantId = u32;
+
+/// Every message on the wire is an `Envelope` around an `Event`.
+///
+/// An envelope without recipient is meant for the coordinator, or is
+/// broadcast to the whole meeting when sent by the coordinator. The sender is
+/// always stamped by the coordinator, whatever a peer puts there.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Envelope {
+    /// Sender participant, `None` for the coordinator itself.
+    pub from: Option<ParticipantId>,
+    /// Recipient participant, `None` for the coordinator or the whole meeting.
+    pub to: Option<ParticipantId>,
+    pub event: Event,
+}
+
+impl Envelope {
+    /// An envelope addressed to a single participant.
+    pub fn to(to: ParticipantId, event: Event) -> Envelope {
+        Envelope {
+            from: None,
+            to: Some(to),
+            event,
+        }
+    }
+}
+
+impl From<Event> for Envelope {
+    fn from(event: Event) -> Self {
+        Envelope {
+            from: None,
+            to: None,
+            event,
+        }
+    }
+}
+
+/// Signaling events exchanged between peers and the coordinator.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum Event {
-    Role(Role),
+    /// The first message of a client, before anything else.
+    Hello(Hello),
+    /// The coordinator's answer to a compatible `Hello`.
+    Welcome(Welcome),
     Passphrase(String),
+    /// Resumes a session after reconnecting, instead of sending a passphrase
+    /// again, with the token received in `Joined`.
+    Resume(String),
+    /// This peer joined a meeting, or resumed its session.
+    Joined(Joined),
+    /// This peer is alone in the meeting, and waits for others to join.
+    Waiting(Waiting),
+    /// Another participant joined the meeting. The receiver initiates a
+    /// connection to it.
+    PeerJoined(ParticipantId),
+    /// Another participant left the meeting for good. The receiver closes its
+    /// connection to it.
+    PeerLeft(ParticipantId),
     Offer(String),
     Answer(String),
     IceCandidate(IceCandidate),
     CloseConnection,
-    Error(String),
+    Error(ErrorCode),
+}
+
+/// Handshake opening every connection to the coordinator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub client_kind: ClientKind,
+    /// Optional features supported by the client.
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// A `Hello` of the current protocol version.
+    pub fn new(client_kind: ClientKind, capabilities: Vec<Capability>) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_kind,
+            capabilities,
+        }
+    }
+}
+
+/// Answer of the coordinator to a compatible client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Welcome {
+    pub protocol_version: u32,
+    /// Optional features supported by both the coordinator and the client.
+    pub capabilities: Vec<Capability>,
+    /// STUN and TURN servers to create peer connections with.
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+}
+
+impl Welcome {
+    /// The codec to use on the connection after the handshake.
+    pub fn codec(&self) -> Codec {
+        if self.capabilities.contains(&Capability::Cbor) {
+            Codec::Cbor
+        } else {
+            Codec::Json
+        }
+    }
+}
+
+/// Seat of a participant in a meeting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Joined {
+    pub id: ParticipantId,
+    /// Token to resume the session after the connection to the coordinator
+    /// drops, within a grace period.
+    pub resume_token: String,
+}
+
+/// Wait of a participant alone in a meeting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Waiting {
+    /// Seconds left before the coordinator gives up waiting, and ends the
+    /// session with `PeerTimedOut`.
+    pub timeout_secs: u64,
+}
+
+/// STUN or TURN server, as in the `iceServers` of an `RTCConfiguration`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Kind of client connecting to the coordinator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ClientKind {
+    /// The `peer` web page.
+    Web,
+    /// A native application.
+    Native,
+    /// An automated participant.
+    Bot,
+}
+
+/// Optional protocol feature, negotiated during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Capability {
+    /// Meetings with more than two participants. A client without it only
+    /// joins meetings of two participants at most, which are then kept so.
+    MultiParty,
+    /// CBOR encoding of envelopes in binary frames, after the handshake.
+    Cbor,
+    /// Any capability unknown to this side, usually from a newer version.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Machine-readable error sent by the coordinator.
+///
+/// Clients should react to the variant rather than to the English text of its
+/// `Display` implementation, which is only meant as a default message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ErrorCode {
+    /// The first message was not a `Hello`.
+    MissingHello,
+    /// The client speaks another version of the protocol than the coordinator.
+    UnsupportedVersion { supported: u32 },
+    /// No passphrase was received after the handshake.
+    MissingPassphrase,
+    /// The passphrase is not acceptable.
+    InvalidPassphrase,
+    /// The meeting has no room left for another participant.
+    RoomFull,
+    /// The client sent too many requests.
+    RateLimited,
+    /// The coordinator is shutting down.
+    ShuttingDown,
+    /// The other participant did not show up in time.
+    PeerTimedOut,
+    /// The session to resume has expired, or never existed.
+    ResumeFailed,
+    /// The storage of the coordinator failed, such as a lost connection to
+    /// its backend.
+    Unavailable,
+    /// Any error without a payload unknown to this side, usually from a newer
+    /// version. Unknown errors with a payload fail to decode, so new errors
+    /// should not carry one.
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            ErrorCode::MissingHello => write!(f, "First message should be a hello."),
+            ErrorCode::UnsupportedVersion { supported } => write!(
+                f,
+                "Unsupported protocol version, the coordinator supports version {supported}."
+            ),
+            ErrorCode::MissingPassphrase => {
+                write!(f, "First message should be a passphrase string.")
+            }
+            ErrorCode::InvalidPassphrase => write!(f, "Invalid passphrase."),
+            ErrorCode::RoomFull => write!(f, "This meeting is already full!"),
+            ErrorCode::RateLimited => write!(f, "Too many requests, please retry later."),
+            ErrorCode::ShuttingDown => write!(f, "The server is shutting down."),
+            ErrorCode::PeerTimedOut => write!(f, "The other participant did not join in time."),
+            ErrorCode::ResumeFailed => write!(f, "The session has expired."),
+            ErrorCode::Unavailable => {
+                write!(
+                    f,
+                    "The server is temporarily unavailable, please retry later."
+                )
+            }
+            ErrorCode::Unknown => write!(f, "An unknown error occurred."),
+        }
+    }
 }
 
 /// Peer role.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum Role {
     Initiator,
     Responder,
@@ -34,9 +268,16 @@ impl Role {
             Role::Responder => Role::Initiator,
         }
     }
+
+    /// Whether this side yields on offer collisions, in the WebRTC "perfect
+    /// negotiation" pattern. The responder is the polite one.
+    pub fn is_polite(&self) -> bool {
+        matches!(self, Role::Responder)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct IceCandidate {
     pub candidate: String,
     pub sdp_mid: Option<String>,
@@ -0,0 +1,22 @@
+//! Keeps the published `schema.json` in sync with the protocol types.
+//!
+//! Run `just schema` to regenerate it after changing the protocol.
+#![cfg(feature = "schema")]
+
+use std::{env, fs, path::Path};
+
+#[test]
+fn schema_is_up_to_date() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("schema.json");
+    let schema = serde_json::to_string_pretty(&protocol::schema()).unwrap() + "\n";
+
+    if env::var_os("UPDATE_SCHEMA").is_some() {
+        fs::write(&path, schema).unwrap();
+        return;
+    }
+    assert_eq!(
+        fs::read_to_string(&path).unwrap_or_default(),
+        schema,
+        "`protocol/schema.json` is outdated, run `just schema` to regenerate it"
+    );
+}
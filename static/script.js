@@ -34,5 +34,5 @@ window.addEventListener('beforeunload',handleBeforeUnload)
 
 function confirmReturnHome(){
   window.removeEventListener('beforeunload',handleBeforeUnload);
-  location.href = '/'  // home url
+  location.href = document.baseURI  // home url, under the base path
 }
\ No newline at end of file